@@ -26,6 +26,8 @@ use std::io;
 use std::path::Path;
 use std::process::Command;
 
+use sha2::{Digest, Sha256};
+
 fn main() {
     let out_dir = std::env::var("OUT_DIR").unwrap();
 
@@ -68,6 +70,13 @@ fn main() {
 
     assert!(output.status.success());
     println!("cargo:rustc-env=PLONK_INJECT_DYLIB={}", inject_dylib);
+
+    // Embedded in plonk, the copy it extracts is checked against this.
+    let injector = fs::read(&inject_dylib).expect("Failed to read the injector");
+    println!(
+        "cargo:rustc-env=PLONK_INJECT_SHA256={:x}",
+        Sha256::digest(&injector)
+    );
 }
 
 fn download_deps() -> Result<(), Box<dyn std::error::Error>> {
@@ -21,15 +21,62 @@
  */
 
 use cargo_metadata::{MetadataCommand, Node, Package, PackageId};
+use notify::RecommendedWatcher;
 use notify::RecursiveMode;
 use notify_debouncer_mini::new_debouncer;
+use notify_debouncer_mini::DebounceEventResult;
+use notify_debouncer_mini::Debouncer;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ffi::OsString;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Mutex, OnceLock, RwLock};
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
+/// Log a diagnostic line to stderr (or `--log-file`), never to stdout.
+macro_rules! plonk_log {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log($crate::LogLevel::$level, format_args!($($arg)*))
+    };
+}
+
+#[cfg(not(target_os = "windows"))]
+mod plonk_bind;
+#[cfg(not(target_os = "windows"))]
+mod plonk_calls;
+#[cfg(unix)]
+mod plonk_child;
+#[cfg(unix)]
+mod plonk_doctor;
+#[cfg(not(target_os = "windows"))]
+mod plonk_dwarf;
+mod plonk_generations;
+mod plonk_injector;
+#[cfg(not(target_os = "windows"))]
+mod plonk_missing;
+mod plonk_profile;
+#[cfg(unix)]
+mod plonk_supervise;
+mod plonk_timings;
+#[cfg(not(target_os = "windows"))]
+mod plonk_toolchain;
+#[cfg(unix)]
+mod plonk_tui;
 #[cfg(target_os = "windows")]
 mod plonk_inject_win;
 
@@ -40,17 +87,34 @@ USAGE:
     plonk [COMMAND] [FLAGS]
 
 FLAGS:
-    -h, --help       Prints help information
-    -v, --verbose    Verbose output
-    -p, --package    Package to build
-    -s, --symbol     Hot reload for a specific symbol
-    -r, --release    Build in release mode
-    -w, --watch      Watch for changes and rebuild
-    -b, --bin        Manually specify binary package
+    -h, --help              Prints help information
+    -v, --verbose           Verbose output (same as --log debug)
+        --log               Log level: error, warn, info or debug [env: PLONK_LOG]
+        --log-file          Append logs to a file instead of stderr [env: PLONK_LOG_FILE]
+    -p, --package           Package to build
+    -s, --symbol            Hot reload for a specific symbol, can be repeated
+    -r, --release           Build in release mode
+        --profile           Build with a custom cargo profile
+    -w, --watch             Watch for changes and rebuild
+        --debounce          Milliseconds to wait for more changes before rebuilding [default: 100]
+        --supervise         Restart the program when `run --watch` can't hot patch a change
+        --tui               Full-screen dashboard for `run --watch`, implies --supervise
+        --restart-signal    Signal that stops the program before a restart [default: TERM]
+        --restart-timeout   Seconds to wait for it before sending SIGKILL [default: 5]
+    -b, --bin               Manually specify binary package
+        --scrub-env         Hide plonk from processes spawned by the binary
+        --stop-the-world    Pause the program's other threads while patching (Linux)
+        --unload            Unload patch libraries once no function or thread uses them
+        --no-inline         Rebuild crates that inlined a patched function without optimizations
+        --to                Generation `revert` goes back to [default: 0, the original code]
+        --timings           Print how long each phase of a reload takes
+        --timings-json      Also write the session's timings to a JSON file, implies --timings
 
 SUBCOMMANDS:
-    build    Compile the package
-    run      Run the binary
+    build                   Compile the package
+    run                     Run the binary
+    revert                  Undo hot patches in the program of a `run --watch --supervise` session
+    doctor                  Check that everything plonk needs for `run` is set up
 ";
 
 #[derive(Default)]
@@ -64,15 +128,57 @@ struct Options {
     // -b, --bin
     bin: Option<String>,
 
-    // -r, --release
-    release: bool,
+    // -r, --release, --profile
+    profile: String,
 
     // -s, --symbol
-    symbol: Option<String>,
+    symbols: Vec<String>,
 
     // -w, --watch
     watch: bool,
 
+    // --debounce
+    debounce: Option<u64>,
+
+    // --supervise
+    supervise: bool,
+
+    // --tui
+    tui: bool,
+
+    // --restart-signal
+    restart_signal: Option<String>,
+
+    // --restart-timeout
+    restart_timeout: Option<u64>,
+
+    // --scrub-env
+    scrub_env: bool,
+
+    // --stop-the-world
+    stop_the_world: bool,
+
+    // --unload
+    unload: bool,
+
+    // --no-inline
+    no_inline: bool,
+
+    // --log
+    log: LogLevel,
+
+    // --log-file
+    log_file: Option<PathBuf>,
+
+    // --to
+    revert_to: Option<u32>,
+
+    // --timings
+    timings: bool,
+
+    // --timings-json
+    timings_json: Option<PathBuf>,
+
     _internal_meta: bool,
     forward: Vec<OsString>,
 
@@ -82,11 +188,169 @@ struct Options {
 
 #[derive(Default)]
 struct WatchCache {
+    /// Symbols already found in the binary.
     #[allow(dead_code)]
-    bin_symbol: Option<String>,
+    bin_symbols: HashMap<String, String>,
+    /// Signatures of binary symbols, `None` without debug info.
+    #[cfg(not(target_os = "windows"))]
+    bin_signatures: HashMap<String, Option<plonk_dwarf::Signature>>,
+    /// Statics of the binary, loaded on the first reload.
+    #[cfg(not(target_os = "windows"))]
+    bin_statics: Option<plonk_bind::Statics>,
+    /// Functions of the binary, loaded when first patching.
+    #[cfg(not(target_os = "windows"))]
+    bin_functions: Option<plonk_calls::Functions>,
+    /// Calls of patched functions the binary inlined, by symbol.
+    #[cfg(not(target_os = "windows"))]
+    bin_inlined: HashMap<String, Vec<plonk_dwarf::Inlined>>,
+    /// Symbols whose inlined calls were already warned about.
+    #[cfg(not(target_os = "windows"))]
+    inlined_warned: HashSet<String>,
+    /// Toolchain the binary was built with, loaded on the first reload.
+    #[cfg(not(target_os = "windows"))]
+    bin_toolchain: Option<plonk_toolchain::Toolchain>,
+
+    /// File system events, set while watching.
+    events: Option<Receiver<DebounceEventResult>>,
+    /// Changes in here are our own builds.
+    target_dir: PathBuf,
+    /// Changed files that haven't been handled yet.
+    changed: Vec<PathBuf>,
+    /// Changes that restarted the last build, which picked them up.
+    rebuilt: Vec<PathBuf>,
+    /// When the first change of a reload arrived, for `--timings`.
+    changed_at: Option<Instant>,
+    /// Copies of the library handed to the injector.
+    generations: plonk_generations::Generations,
+}
+
+/// Path of the injector, extracted on first use. Exits if it can't be.
+fn inject_dylib() -> &'static str {
+    static PATH: OnceLock<String> = OnceLock::new();
+    PATH.get_or_init(|| match plonk_injector::extract() {
+        Ok(path) => path,
+        Err(err) => {
+            plonk_log!(Error, "{}", err);
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Version of the `PLONK_*` environment protocol understood by the injector.
+/// Bump this whenever a variable is added, removed or changes meaning.
+const PLONK_PROTOCOL: &str = "11";
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    Error,
+    #[default]
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<Self> {
+        match level.to_ascii_lowercase().as_str() {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+/// Receives log lines instead of stderr while the dashboard is up.
+static LOG_TAP: RwLock<Option<LogTap>> = RwLock::new(None);
+
+type LogTap = Box<dyn Fn(String) + Send + Sync>;
+
+/// Send log lines to `tap` instead of stderr, or back to stderr with `None`.
+fn set_log_tap(tap: Option<LogTap>) {
+    *LOG_TAP.write().unwrap() = tap;
+}
+
+/// Hand `line` to the log tap, returns it when there is none.
+fn tap_line(line: String) -> Option<String> {
+    match LOG_TAP.read().unwrap().as_ref() {
+        Some(tap) => {
+            tap(line);
+            None
+        }
+        None => Some(line),
+    }
+}
+
+fn init_log(level: LogLevel, file: Option<&PathBuf>) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+    if let Some(path) = file {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .expect("Failed to open log file");
+        let _ = LOG_FILE.set(Mutex::new(file));
+    }
 }
 
-const INJECT_DYLIB: &'static str = env!("PLONK_INJECT_DYLIB");
+fn log(level: LogLevel, args: std::fmt::Arguments) {
+    if level as u8 > LOG_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let line = format!("[*] {}", args);
+    match LOG_FILE.get() {
+        Some(file) => {
+            let _ = writeln!(file.lock().unwrap(), "{}", line);
+        }
+        None => {
+            if let Some(line) = tap_line(line) {
+                eprintln!("{}", line);
+            }
+        }
+    }
+}
+
+/// Where the stderr of the tools we run should go.
+fn tool_stderr() -> Stdio {
+    if LOG_TAP.read().unwrap().is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Send each line read from `stream` to `sink` on a background thread.
+fn forward_lines(stream: impl Read + Send + 'static, sink: impl Fn(String) + Send + 'static) {
+    std::thread::spawn(move || {
+        for line in BufReader::new(stream).lines().map_while(Result::ok) {
+            sink(line);
+        }
+    });
+}
+
+/// Forward a tool's piped stderr (see `tool_stderr`) to the log tap.
+fn tap_stderr(child: &mut std::process::Child) {
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, |line| {
+            if let Some(line) = tap_line(line) {
+                eprintln!("{}", line);
+            }
+        });
+    }
+}
 
 fn main() {
     // `from_vec` takes `OsString`, not `String`.
@@ -113,15 +377,52 @@ fn main() {
         return;
     }
 
+    let verbose = pargs.contains(["-v", "--verbose"]);
+    let release = pargs.contains(["-r", "--release"]);
+    let log = pargs
+        .opt_value_from_str::<_, String>("--log")
+        .unwrap()
+        .or_else(|| std::env::var("PLONK_LOG").ok());
+    let log = match log.as_deref().map(LogLevel::parse) {
+        Some(Some(level)) => level,
+        Some(None) => {
+            plonk_log!(Error, "Unknown log level: {}", log.unwrap());
+            eprint!("{}", HELP);
+            std::process::exit(1);
+        }
+        None if verbose => LogLevel::Debug,
+        None => LogLevel::default(),
+    };
+
     let mut opts = Options {
-        verbose: pargs.contains(["-v", "--verbose"]),
+        verbose,
         package: pargs
             .value_from_str(["-p", "--package"])
             .unwrap_or_else(|_| ".".to_string()),
         bin: pargs.value_from_str(["-b", "--bin"]).ok(),
-        release: pargs.contains(["-r", "--release"]),
-        symbol: pargs.value_from_str(["-s", "--symbol"]).ok(),
+        profile: pargs
+            .opt_value_from_str("--profile")
+            .unwrap()
+            .unwrap_or_else(|| if release { "release" } else { "dev" }.into()),
+        symbols: pargs.values_from_str(["-s", "--symbol"]).unwrap(),
         watch: pargs.contains(["-w", "--watch"]),
+        debounce: pargs.opt_value_from_str("--debounce").unwrap(),
+        supervise: pargs.contains("--supervise"),
+        tui: pargs.contains("--tui"),
+        restart_signal: pargs.opt_value_from_str("--restart-signal").unwrap(),
+        restart_timeout: pargs.opt_value_from_str("--restart-timeout").unwrap(),
+        scrub_env: pargs.contains("--scrub-env"),
+        stop_the_world: pargs.contains("--stop-the-world"),
+        unload: pargs.contains("--unload"),
+        no_inline: pargs.contains("--no-inline"),
+        log,
+        log_file: pargs
+            .opt_value_from_str("--log-file")
+            .unwrap()
+            .or_else(|| std::env::var_os("PLONK_LOG_FILE").map(PathBuf::from)),
+        revert_to: pargs.opt_value_from_str("--to").unwrap(),
+        timings: pargs.contains("--timings"),
+        timings_json: pargs.opt_value_from_str("--timings-json").unwrap(),
         forward,
         ..Default::default()
     };
@@ -131,6 +432,11 @@ fn main() {
         cmd = pargs.subcommand().unwrap();
     }
 
+    init_log(opts.log, opts.log_file.as_ref());
+    if opts.timings || opts.timings_json.is_some() {
+        plonk_timings::enable(opts.timings_json.clone());
+    }
+
     let remaining = pargs.finish();
     if !remaining.is_empty() {
         println!("Unknown arguments: {:?}", remaining);
@@ -140,9 +446,37 @@ fn main() {
 
     match cmd.as_deref() {
         Some("build") => {
-            build(&mut opts);
+            opts._internal_meta = true;
+            if build(&mut opts).is_none() {
+                std::process::exit(1);
+            }
+            plonk_timings::finish(false);
+            plonk_timings::summary();
+        }
+        Some("run") => {
+            // Before the dashboard takes over the terminal.
+            inject_dylib();
+            match run(&mut opts) {
+                Some(status) => exit_with(status),
+                None => {
+                    #[cfg(unix)]
+                    plonk_supervise::remove_control();
+                    std::process::exit(1)
+                }
+            }
+        }
+        #[cfg(unix)]
+        Some("revert") => {
+            if !plonk_supervise::revert(&mut opts) {
+                std::process::exit(1);
+            }
+        }
+        #[cfg(unix)]
+        Some("doctor") => {
+            if !plonk_doctor::doctor(&mut opts) {
+                std::process::exit(1);
+            }
         }
-        Some("run") => run(&mut opts),
         _ => {
             println!("No command specified");
             print!("{}", HELP);
@@ -150,10 +484,12 @@ fn main() {
     }
 }
 
-fn watch<R>(pargs: &mut Options, fn_: fn(&mut Options) -> R) {
+/// Watch every local package the current one depends on. The debouncer must be
+/// kept alive for events to arrive.
+fn watcher(pargs: &mut Options) -> Debouncer<RecommendedWatcher> {
     let (tx, rx) = std::sync::mpsc::channel();
-    let mut debouncer =
-        new_debouncer(Duration::from_millis(100), tx).expect("Failed to create watcher");
+    let debounce = Duration::from_millis(pargs.debounce.unwrap_or(100));
+    let mut debouncer = new_debouncer(debounce, tx).expect("Failed to create watcher");
 
     let local_deps = find_local_deps().expect("Failed to find local deps");
 
@@ -164,9 +500,62 @@ fn watch<R>(pargs: &mut Options, fn_: fn(&mut Options) -> R) {
             .expect("Failed to watch");
     }
 
-    fn_(pargs);
-    for _event in rx.iter() {
+    let meta = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .expect("Failed to get metadata");
+    pargs.watch_cache.target_dir = meta.target_directory.into_std_path_buf();
+    pargs.watch_cache.events = Some(rx);
+
+    debouncer
+}
+
+/// Wait up to `timeout` for changes, returns whether any arrived. They are
+/// queued in `changed`.
+fn poll_changes(cache: &mut WatchCache, timeout: Duration) -> bool {
+    let results = match &cache.events {
+        Some(rx) => match rx.recv_timeout(timeout) {
+            Ok(result) => std::iter::once(result).chain(rx.try_iter()).collect::<Vec<_>>(),
+            Err(RecvTimeoutError::Timeout) => return false,
+            Err(RecvTimeoutError::Disconnected) => panic!("Watcher stopped"),
+        },
+        None => return false,
+    };
+
+    let queued = cache.changed.len();
+    let arrived = Instant::now();
+    for result in results {
+        match result {
+            Ok(events) => cache.changed.extend(
+                events
+                    .into_iter()
+                    .map(|event| event.path)
+                    .filter(|path| !path.starts_with(&cache.target_dir)),
+            ),
+            Err(err) => plonk_log!(Warn, "Watch error: {}", err),
+        }
+    }
+
+    if cache.changed.len() > queued {
+        cache.changed_at.get_or_insert(arrived);
+        return true;
+    }
+    false
+}
+
+fn watch<R>(pargs: &mut Options, fn_: fn(&mut Options) -> R) {
+    let _debouncer = watcher(pargs);
+
+    loop {
+        plonk_timings::start(pargs.watch_cache.changed_at.take());
         fn_(pargs);
+        plonk_timings::finish(false);
+
+        // Everything saved in the meantime goes into a single rebuild.
+        while pargs.watch_cache.changed.is_empty() {
+            poll_changes(&mut pargs.watch_cache, Duration::from_secs(1));
+        }
+        pargs.watch_cache.changed.clear();
     }
 }
 
@@ -176,18 +565,40 @@ fn build(pargs: &mut Options) -> Option<cargo_metadata::Artifact> {
         watch(pargs, build);
     }
 
+    // The library finds libstd through its rpath, whatever the program's
+    // library path is. Flags the binary was built with apply to it as well.
+    // Encoded, as the toolchain's path may have spaces, and overriding the
+    // config files' flags, so those are read first.
+    let mut rustflags = plonk_profile::rustflags().unwrap_or_else(|err| {
+        plonk_log!(Warn, "{}, building the library without the binary's rustflags", err);
+        Vec::new()
+    });
+    rustflags.extend(["-C".to_string(), "prefer-dynamic".to_string()]);
+    #[cfg(not(target_os = "windows"))]
+    rustflags.extend([
+        "-C".to_string(),
+        format!("link-arg=-Wl,-rpath,{}", rustc_libdir().to_string_lossy()),
+    ]);
+
     let mut cargo = Command::new("cargo");
     cargo
-        .env("RUSTFLAGS", "-C prefer-dynamic")
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags.join("\x1f"))
         .arg("rustc")
         .arg("--crate-type=dylib")
         .arg("-p")
         .arg(&pargs.package);
 
-    if pargs.release {
-        cargo.arg("--release");
+    match plonk_profile::Profile::load(&pargs.profile, &pargs.package) {
+        Ok(profile) => {
+            for config in plonk_profile::library_config(&profile) {
+                cargo.arg("--config").arg(config);
+            }
+        }
+        Err(err) => plonk_log!(Warn, "{}", err),
     }
 
+    cargo.arg("--profile").arg(&pargs.profile);
+
     if pargs.verbose {
         cargo.arg("-vv");
     }
@@ -196,19 +607,42 @@ fn build(pargs: &mut Options) -> Option<cargo_metadata::Artifact> {
         cargo.arg("--message-format=json-render-diagnostics");
     }
 
-    cargo.stderr(std::process::Stdio::inherit());
+    cargo.stderr(tool_stderr());
 
-    let cargo = cargo.output().expect("Failed to spawn cargo build");
-    assert!(cargo.status.success());
+    #[cfg(unix)]
+    if pargs.watch_cache.events.is_some() {
+        plonk_child::own_group(&mut cargo);
+    }
+
+    pargs.watch_cache.rebuilt.clear();
+    let (status, stdout, took, since) = loop {
+        let started = Instant::now();
+        let since = SystemTime::now();
+        match cancellable_build(&mut cargo, &mut pargs.watch_cache) {
+            Some((status, stdout)) => break (status, stdout, started.elapsed(), since),
+            None => {
+                // Handled by this build, not the next one.
+                let cache = &mut pargs.watch_cache;
+                cache.rebuilt.append(&mut cache.changed);
+                cache.changed_at = None;
+                plonk_log!(Info, "Files changed, restarting the build");
+            }
+        }
+    };
+    if !status.success() {
+        plonk_log!(Error, "Failed to build {}", pargs.package);
+        return None;
+    }
 
     if pargs._internal_meta {
-        let cursor = std::io::Cursor::new(&cargo.stdout[..]);
+        let cursor = std::io::Cursor::new(&stdout[..]);
         let reader = std::io::BufReader::new(cursor);
         for message in cargo_metadata::Message::parse_stream(reader) {
             let message = message.expect("Failed to parse message");
             match message {
                 cargo_metadata::Message::CompilerArtifact(artifact) => {
                     if artifact.target.kind.contains(&"dylib".to_string()) {
+                        plonk_timings::build(took, since, artifact.filenames[0].as_ref());
                         return Some(artifact);
                     }
                 }
@@ -220,13 +654,48 @@ fn build(pargs: &mut Options) -> Option<cargo_metadata::Artifact> {
     None
 }
 
-fn get_bin_crates(meta: &cargo_metadata::Metadata, release: bool) -> Vec<(String, String)> {
+/// Run a cargo build, or cancel it and return `None` as soon as a watched file
+/// changes, since its result would already be stale.
+fn cancellable_build(cargo: &mut Command, cache: &mut WatchCache) -> Option<(ExitStatus, Vec<u8>)> {
+    if cache.events.is_none() {
+        let output = cargo.output().expect("Failed to spawn cargo build");
+        return Some((output.status, output.stdout));
+    }
+
+    let mut child = cargo
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to spawn cargo build");
+    tap_stderr(&mut child);
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        let _ = stdout.read_to_end(&mut buf);
+        buf
+    });
+
+    loop {
+        if let Some(status) = child.try_wait().expect("Failed to wait for cargo") {
+            return Some((status, reader.join().unwrap()));
+        }
+
+        if poll_changes(cache, Duration::from_millis(50)) {
+            #[cfg(unix)]
+            let _ = plonk_child::kill_group(&mut child);
+            #[cfg(not(unix))]
+            let _ = child.kill().and_then(|_| child.wait());
+            return None;
+        }
+    }
+}
+
+fn get_bin_crates(meta: &cargo_metadata::Metadata, profile: &str) -> Vec<(String, String)> {
     let mut bins = Vec::new();
     for pkg in meta.packages.iter() {
         for bin in pkg.targets.iter() {
             if bin.kind.contains(&"bin".to_string()) {
                 let mut path = meta.target_directory.clone();
-                path.push(if release { "release" } else { "debug" });
+                path.push(plonk_profile::dir(profile));
                 path.push(&bin.name);
 
                 bins.push((pkg.name.clone(), path.to_string()));
@@ -295,186 +764,544 @@ fn find_local_deps() -> Result<Vec<PathBuf>, String> {
     Ok(local_deps.into_iter().collect::<Vec<PathBuf>>())
 }
 
-fn rustc_sysroot() -> PathBuf {
-    let mut cmd = Command::new("rustc");
-    cmd.arg("--print").arg("sysroot");
+/// The rustc cargo builds with, rustup picks the toolchain from
+/// `rust-toolchain.toml` or `cargo +toolchain` for it.
+fn rustc() -> Command {
+    Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()))
+}
+
+/// `rustc 1.75.0 (82e1608df 2023-12-21)`
+fn rustc_version() -> String {
+    let cmd = rustc().arg("--version").output().expect("Failed to spawn rustc");
+    String::from_utf8_lossy(&cmd.stdout).trim().to_string()
+}
+
+/// Directory holding the toolchain's libstd for the host target.
+fn rustc_libdir() -> PathBuf {
+    let mut cmd = rustc();
+    cmd.arg("--print").arg("target-libdir");
     let cmd = cmd.output().expect("Failed to spawn rustc");
     let stdout = std::str::from_utf8(&cmd.stdout[..]).expect("Failed to parse rustc output");
     PathBuf::from(stdout.trim())
 }
 
-fn run(pargs: &mut Options) {
+fn run(pargs: &mut Options) -> Option<ExitStatus> {
+    #[cfg(unix)]
+    if pargs.watch && pargs.tui {
+        pargs.watch = false;
+        return plonk_tui::dashboard(pargs);
+    }
+
+    #[cfg(unix)]
+    if pargs.watch && pargs.supervise {
+        pargs.watch = false;
+        return plonk_supervise::supervise(pargs, None);
+    }
+
     if pargs.watch {
         pargs.watch = false;
         watch(pargs, run);
     }
 
     pargs._internal_meta = true;
-    let artifact = build(pargs).expect("Failed to build");
+    let artifact = build(pargs)?;
+    let (bin_package, bin) = plonk_timings::time("metadata", || find_bin(pargs))?;
+
+    let library_path = match pargs.watch_cache.generations.copy(artifact.filenames[0].as_ref()) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(err) => {
+            plonk_log!(Error, "Failed to copy {}: {}", artifact.filenames[0], err);
+            return None;
+        }
+    };
+    // The program that loaded the previous copies has exited.
+    pargs.watch_cache.generations.collect(1);
+    let mut lib = command(pargs, &bin);
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        if pargs.symbols.is_empty() {
+            plonk_log!(Error, "No symbol specified. Use -s to specify a function");
+            eprint!("{}", HELP);
+            return None;
+        }
+
+        match plonk_profile::check(&bin, &bin_package, &pargs.package, &pargs.profile) {
+            Ok(warnings) => {
+                for warning in warnings {
+                    plonk_log!(Warn, "{}", warning);
+                }
+            }
+            Err(err) => {
+                plonk_log!(Error, "Refusing to patch, {}", err);
+                return None;
+            }
+        }
+
+        let checked = plonk_timings::time("toolchain", || {
+            plonk_toolchain::check(&mut pargs.watch_cache.bin_toolchain, &bin, &library_path)
+        });
+        if let Err(err) = checked {
+            plonk_log!(Error, "Refusing to patch, {}", err);
+            return None;
+        }
+
+        let mut pairs = plonk_timings::time("symbols", || {
+            resolve_symbols(pargs, &bin, &library_path)
+        })?;
+        let calls = plonk_timings::time("calls", || {
+            plonk_calls::plan(&mut pargs.watch_cache.bin_functions, &bin, &mut pairs)
+        });
+        match calls {
+            Ok(calls) => {
+                lib.env("PLONK_CALLS", calls);
+            }
+            Err(err) => plonk_log!(Warn, "Hooking every function directly: {}", err),
+        }
+        if pairs.is_empty() {
+            plonk_log!(Error, "None of {:?} can be patched", pargs.symbols);
+            return None;
+        }
+        let checked = plonk_timings::time("signatures", || {
+            plonk_dwarf::check_signatures(
+                &mut pargs.watch_cache.bin_signatures,
+                &bin,
+                &library_path,
+                &pairs,
+            )
+        });
+        if let Err(err) = checked {
+            plonk_log!(Error, "Refusing to patch, {}", err);
+            plonk_log!(
+                Error,
+                "Callers in {} still use the old one, rebuild it instead",
+                bin
+            );
+            return None;
+        }
+
+        let olds = pairs
+            .iter()
+            .map(|(old, _)| old.as_str())
+            .collect::<Vec<_>>();
+        let inlined = plonk_timings::time("inlining", || {
+            plonk_dwarf::inlined(&mut pargs.watch_cache.bin_inlined, &bin, &olds)
+        });
+        match inlined {
+            Ok(calls) if !calls.is_empty() => {
+                let config = warn_inlined(&mut pargs.watch_cache.inlined_warned, &calls)
+                    .iter()
+                    .map(|package| {
+                        format!(
+                            "--config '{}'",
+                            plonk_profile::no_inline(&pargs.profile, package)
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                if !config.is_empty() {
+                    plonk_log!(
+                        Warn,
+                        "Build {} with `cargo build{} {}` to patch them as well",
+                        bin,
+                        plonk_profile::args(&pargs.profile),
+                        config.join(" ")
+                    );
+                }
+            }
+            Ok(_) => {}
+            Err(err) => plonk_log!(Debug, "Not looking for inlined calls: {}", err),
+        }
+
+        let bindings = plonk_timings::time("statics", || {
+            plonk_bind::bindings(
+                &mut pargs.watch_cache.bin_statics,
+                &bin,
+                &library_path,
+                &pargs.package,
+            )
+        });
+        match bindings {
+            Ok(bindings) => {
+                lib.env("PLONK_BIND", bindings);
+            }
+            Err(err) => plonk_log!(Warn, "Patched code gets its own statics: {}", err),
+        }
+
+        let (old_symbols, new_symbols): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        lib.env("PLONK_SYMBOL", old_symbols.join(","))
+            .env("PLONK_NEW_SYMBOL", new_symbols.join(","));
+    }
+
+    lib.env("PLONK_LIBRARY", &library_path);
+    #[cfg(target_os = "windows")]
+    {
+        lib.env("PLONK_SYMBOL", pargs.symbols.join(","));
+        lib.env("PLONK_NEW_SYMBOL", pargs.symbols.join(","));
+    }
+
+    // The injector reports how long it took to patch the program, where no
+    // other user can put a report of their own.
+    #[cfg(unix)]
+    let report = match plonk_timings::enabled().then(private_temp_dir) {
+        Some(Ok(dir)) => {
+            let report = dir.join(format!("{}.timings", std::process::id()));
+            let _ = std::fs::remove_file(&report);
+            lib.env("PLONK_TIMINGS", &report);
+            Some(report)
+        }
+        Some(Err(err)) => {
+            plonk_log!(Warn, "Not timing the injector: {}", err);
+            None
+        }
+        None => None,
+    };
+
+    plonk_log!(Debug, "Running: {:?}", lib);
+
+    #[cfg(target_os = "windows")]
+    {
+        let escaped = inject_dylib().replace("\\", "\\\\");
+        let status = unsafe { plonk_inject_win::inject(&mut lib, &escaped) };
+
+        return Some(status);
+    }
+
+    #[cfg(unix)]
+    let mut lib = match plonk_child::spawn(&mut lib, true) {
+        Ok(lib) => lib,
+        Err(_) => {
+            plonk_log!(Error, "Failed to spawn binary: {}", bin);
+            plonk_log!(Error, "Did you forget to build the binary with `cargo build`?");
+
+            eprintln!("{}", HELP);
+            return None;
+        }
+    };
+
+    #[cfg(unix)]
+    if let Some(report) = report {
+        let patched = loop {
+            if plonk_timings::injector_file(&report) {
+                break true;
+            }
+            // Exited before patching, `wait` picks up the status.
+            if plonk_child::try_wait(&mut lib)
+                .expect("Failed to wait for bin")
+                .is_some()
+            {
+                break false;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        plonk_timings::finish(patched);
+    }
 
+    #[cfg(unix)]
+    {
+        let status = plonk_child::wait(&mut lib).expect("Failed to wait for bin");
+        // Ctrl-C only reaches the child, stop watching as well.
+        if std::os::unix::process::ExitStatusExt::signal(&status) == Some(libc::SIGINT) {
+            exit_with(status);
+        }
+        // So was a signal sent to plonk, which the child got as well.
+        if let Some(received) = plonk_child::received() {
+            exit_with(received);
+        }
+        Some(status)
+    }
+}
+
+/// Warn that patches don't reach calls the binary inlined, once per symbol
+/// in `warned`. Returns the workspace packages the newly warned ones were
+/// inlined into.
+#[cfg(not(target_os = "windows"))]
+fn warn_inlined(warned: &mut HashSet<String>, calls: &[plonk_dwarf::Inlined]) -> Vec<String> {
+    let calls = calls
+        .iter()
+        .filter(|call| !warned.contains(&call.callee))
+        .collect::<Vec<_>>();
+    if calls.is_empty() {
+        return Vec::new();
+    }
+    let mut by_callee = BTreeMap::<&str, Vec<&plonk_dwarf::Inlined>>::new();
+    for call in &calls {
+        by_callee.entry(&call.callee).or_default().push(call);
+    }
+    for (callee, calls) in by_callee {
+        warned.insert(callee.to_string());
+        let mut sites = calls
+            .iter()
+            .take(3)
+            .map(|call| {
+                let caller = format!("`{:#}`", rustc_demangle::demangle(&call.caller));
+                match &call.location {
+                    Some(location) => format!("{} ({})", caller, location),
+                    None => caller,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        if calls.len() > 3 {
+            sites.push_str(&format!(" and {} more", calls.len() - 3));
+        }
+        plonk_log!(
+            Warn,
+            "`{:#}` is inlined into {}, those calls keep running the old code",
+            rustc_demangle::demangle(callee),
+            sites
+        );
+    }
+
+    // `app::main` or `<app::Config as core::fmt::Debug>::fmt`
+    let crates = calls
+        .iter()
+        .filter_map(|call| {
+            let caller = format!("{:#}", rustc_demangle::demangle(&call.caller));
+            let caller = caller.trim_start_matches(['<', '&', '*']);
+            caller.split("::").next().map(String::from)
+        })
+        .collect::<BTreeSet<_>>();
+    let meta = match MetadataCommand::new().no_deps().exec() {
+        Ok(meta) => meta,
+        Err(_) => return Vec::new(),
+    };
+    meta.packages
+        .iter()
+        .filter(|pkg| {
+            pkg.targets
+                .iter()
+                .any(|target| crates.contains(&target.name.replace('-', "_")))
+        })
+        .map(|pkg| pkg.name.clone())
+        .collect()
+}
+
+/// Pick the binary to run, returns its package and path.
+fn find_bin(pargs: &Options) -> Option<(String, String)> {
     let cmd = cargo_metadata::MetadataCommand::new();
     let meta = cmd.exec().expect("Failed to get metadata");
 
-    let bins = get_bin_crates(&meta, pargs.release);
-    let (_, bin) = match &pargs.bin {
+    let bins = get_bin_crates(&meta, &pargs.profile);
+    let bin = match &pargs.bin {
         Some(package) => match bins.iter().find(|(pkg, _)| pkg == package) {
             None => {
-                println!("No binary found with name: {}", package);
-                println!("Available binaries: {:?}", bins);
-                return;
+                plonk_log!(Error, "No binary found with name: {}", package);
+                plonk_log!(Error, "Available binaries: {:?}", bins);
+                return None;
             }
             Some(b) => b,
         },
         None => {
             if bins.len() > 1 {
-                println!("Multiple binaries found. Use -b to specify a binary");
-                println!("Available binaries: {:?}", bins);
-                return;
-            } else if bins.len() == 0 {
-                println!("No binaries found");
-                return;
+                plonk_log!(Error, "Multiple binaries found. Use -b to specify a binary");
+                plonk_log!(Error, "Available binaries: {:?}", bins);
+                return None;
+            } else if bins.is_empty() {
+                plonk_log!(Error, "No binaries found");
+                return None;
             }
             bins.first().unwrap()
         }
     };
 
-    let library_path = artifact.filenames[0].clone();
-    let mut lib = Command::new(&bin);
-    if pargs.verbose {
-        lib.env("VERBOSE", "y");
+    Some(bin.clone())
+}
+
+/// `plonk-<uid>` in the temporary directory, which only the current user can
+/// get into, for files other local users mustn't create or swap first.
+#[cfg(unix)]
+fn private_temp_dir() -> Result<PathBuf, String> {
+    use std::os::unix::fs::DirBuilderExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("plonk-{}", uid));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(err) => return Err(format!("Failed to create {}: {}", dir.display(), err)),
     }
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        if let Some(symbol) = &pargs.symbol {
-            let old_symbol = pargs
-                .watch_cache
-                .bin_symbol
-                .clone()
-                .or_else(|| find_symbol(&bin, &pargs.package, symbol));
-            match old_symbol {
-                Some(old_symbol) => {
-                    lib.env("SYMBOL", &old_symbol);
-                    pargs.watch_cache.bin_symbol = Some(old_symbol);
-                }
-                None => {
-                    println!("Failed to find function symbol `{}` in {}", symbol, bin);
-                    println!("See FAQ"); // TODO
-                    return;
-                }
-            }
+    let meta = std::fs::symlink_metadata(&dir)
+        .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?;
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} isn't a private directory of the current user",
+            dir.display()
+        ));
+    }
+    Ok(dir)
+}
 
-            let new_symbol = find_symbol(library_path.as_ref(), &pargs.package, symbol);
-            match new_symbol {
-                Some(new_symbol) => {
-                    lib.env("NEW_SYMBOL", &new_symbol);
-                }
-                None => {
-                    println!(
-                        "Failed to find function symbol `{}` in {}",
-                        symbol, library_path
-                    );
-                    println!("See FAQ"); // TODO
-                    return;
-                }
-            };
-        } else {
-            println!("No symbol specified. Use -s to specify a function");
-            print!("{}", HELP);
-            return;
-        }
+#[cfg(not(unix))]
+fn private_temp_dir() -> Result<PathBuf, String> {
+    let dir = std::env::temp_dir().join("plonk");
+    std::fs::create_dir_all(&dir)
+        .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+    Ok(dir)
+}
+
+/// `dir` in front of the search path in `var`, keeping what the user set.
+fn prepend_path(var: &str, dir: PathBuf) -> std::ffi::OsString {
+    let existing = std::env::var_os(var).unwrap_or_default();
+    // An empty entry would have the loader search the current directory.
+    let paths = std::iter::once(dir)
+        .chain(std::env::split_paths(&existing))
+        .filter(|path| !path.as_os_str().is_empty());
+    std::env::join_paths(paths).unwrap_or(existing)
+}
+
+/// Command for the binary with everything the injector needs except the
+/// symbols to patch.
+fn command(pargs: &Options, bin: &str) -> Command {
+    let mut lib = Command::new(bin);
+    lib.env("PLONK_PROTOCOL", PLONK_PROTOCOL)
+        .env("PLONK_BINARY", bin)
+        .env("PLONK_LOG", pargs.log.as_str());
+    if let Some(log_file) = &pargs.log_file {
+        lib.env("PLONK_LOG_FILE", log_file);
+    }
+    if pargs.stop_the_world {
+        lib.env("PLONK_STOP_WORLD", "1");
+    }
+    if pargs.unload {
+        lib.env("PLONK_UNLOAD", "1");
+    }
+    if pargs.scrub_env {
+        lib.env("PLONK_SCRUB", "1");
     }
 
-    lib.env("PLONK_LIBRARY", &library_path)
-        .env("PLONK_BINARY", bin);
     #[cfg(target_os = "macos")]
     {
-        lib.env("DYLD_INSERT_LIBRARIES", INJECT_DYLIB)
-            .env("DYLD_LIBRARY_PATH", rustc_sysroot().join("lib"));
+        lib.env("DYLD_INSERT_LIBRARIES", inject_dylib()).env(
+            "DYLD_LIBRARY_PATH",
+            prepend_path("DYLD_LIBRARY_PATH", rustc_libdir()),
+        );
     }
     #[cfg(target_os = "linux")]
     {
-        lib.env("LD_PRELOAD", INJECT_DYLIB)
-            .env("LD_LIBRARY_PATH", rustc_sysroot().join("lib"));
+        lib.env("LD_PRELOAD", inject_dylib()).env(
+            "LD_LIBRARY_PATH",
+            prepend_path("LD_LIBRARY_PATH", rustc_libdir()),
+        );
     }
     #[cfg(target_os = "windows")]
     {
-        if let Some(sym) = &pargs.symbol {
-            lib.env("SYMBOL", sym);
-            lib.env("NEW_SYMBOL", sym);
-        }
-        lib.env(
-            "PATH",
-            rustc_sysroot().join("lib/rustlib/x86_64-pc-windows-msvc/lib"),
-        );
+        lib.env("PATH", prepend_path("PATH", rustc_libdir()));
     }
 
     for arg in &pargs.forward {
         lib.arg(arg);
     }
 
-    if pargs.verbose {
-        println!("[*] Running: {:?}", lib);
-    }
+    lib
+}
 
-    #[cfg(target_os = "windows")]
-    {
-        let escaped = INJECT_DYLIB.replace("\\", "\\\\");
-        unsafe { plonk_inject_win::inject(&mut lib, &escaped) };
+/// Find every `--symbol` in the binary and in the freshly built library.
+#[cfg(not(target_os = "windows"))]
+fn resolve_symbols(
+    pargs: &mut Options,
+    bin: &str,
+    library_path: &str,
+) -> Option<Vec<(String, String)>> {
+    let mut resolved = Vec::new();
+    for symbol in &pargs.symbols {
+        let old_symbol = pargs
+            .watch_cache
+            .bin_symbols
+            .get(symbol)
+            .cloned()
+            .or_else(|| find_symbol(bin, &pargs.package, symbol));
+        let old_symbol = match old_symbol {
+            Some(old_symbol) => old_symbol,
+            None => {
+                plonk_log!(Error, "Failed to find function symbol `{}` in {}", symbol, bin);
+                plonk_missing::explain(&pargs.package, symbol, bin, Some(library_path));
+                return None;
+            }
+        };
+        pargs
+            .watch_cache
+            .bin_symbols
+            .insert(symbol.clone(), old_symbol.clone());
 
-        return;
+        match find_symbol(library_path, &pargs.package, symbol) {
+            Some(new_symbol) => resolved.push((old_symbol, new_symbol)),
+            None => {
+                plonk_log!(
+                    Error,
+                    "Failed to find function symbol `{}` in {}",
+                    symbol,
+                    library_path
+                );
+                plonk_missing::explain(&pargs.package, symbol, bin, Some(library_path));
+                return None;
+            }
+        }
     }
 
-    let mut lib = match lib.spawn() {
-        Ok(lib) => lib,
-        Err(_) => {
-            println!("Failed to spawn binary: {}", bin);
-            println!("Did you forget to build the binary with `cargo build`?");
+    Some(resolved)
+}
 
-            println!("{}", HELP);
-            return;
-        }
-    };
+/// Exit with the same status as the child.
+fn exit_with(status: ExitStatus) -> ! {
+    plonk_timings::summary();
+    #[cfg(unix)]
+    plonk_supervise::remove_control();
 
-    lib.wait().expect("Failed to wait for bin");
+    #[cfg(unix)]
+    plonk_child::exit_with(status);
+
+    #[cfg(not(unix))]
+    std::process::exit(status.code().unwrap_or(1));
 }
 
+/// Names of the functions in the symbol table of `path`, as printed by `nm`.
 #[cfg(not(target_os = "windows"))]
-fn find_symbol(path: &str, package: &str, symbol: &str) -> Option<String> {
-    let full_symbol = format!("{}::{}", package, symbol);
+fn function_symbols(path: &str) -> Vec<String> {
     let mut cmd = Command::new("nm");
     cmd.arg(path);
     let cmd = cmd.output().expect("Failed to spawn nm");
 
     let stdout = std::str::from_utf8(&cmd.stdout[..]).expect("Failed to parse nm output");
-    let stdout = stdout.split("\n").collect::<Vec<&str>>();
-
-    for line in stdout {
+    let mut symbols = Vec::new();
+    for line in stdout.split('\n') {
         let line = line.trim();
-        let cols = line.split(" ").collect::<Vec<&str>>();
+        let cols = line.split(' ').collect::<Vec<&str>>();
         if cols.len() < 3 {
             continue;
         }
         if cols[1] == "t" || cols[1] == "T" {
-            if cols[2] == symbol {
-                return Some(symbol.into());
-            }
+            symbols.push(cols[2].to_string());
+        }
+    }
 
-            #[cfg(target_os = "macos")]
-            // _<symbol>.
-            if cols[2] == format!("_{}", symbol) {
-                return Some(symbol.to_string());
-            }
+    symbols
+}
+
+#[cfg(not(target_os = "windows"))]
+fn find_symbol(path: &str, package: &str, symbol: &str) -> Option<String> {
+    let full_symbol = format!("{}::{}", package, symbol);
 
-            let demangled = rustc_demangle::demangle(cols[2]).to_string();
-            if demangled.contains(&full_symbol) {
-                #[cfg(target_os = "macos")]
-                // Remove _ from _<symbol>.
-                return Some(cols[2][1..].to_string());
+    for name in function_symbols(path) {
+        if name == symbol {
+            return Some(symbol.into());
+        }
 
-                #[cfg(not(target_os = "macos"))]
-                return Some(cols[2].to_string());
-            }
+        #[cfg(target_os = "macos")]
+        // _<symbol>.
+        if name == format!("_{}", symbol) {
+            return Some(symbol.to_string());
+        }
+
+        let demangled = rustc_demangle::demangle(&name).to_string();
+        if demangled.contains(&full_symbol) {
+            #[cfg(target_os = "macos")]
+            // Remove _ from _<symbol>.
+            return Some(name[1..].to_string());
+
+            #[cfg(not(target_os = "macos"))]
+            return Some(name);
         }
     }
 
@@ -0,0 +1,370 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Sharing the running binary's statics and dependencies with the patch library */
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use object::elf;
+use object::Architecture;
+use object::Object;
+use object::ObjectSymbol;
+use object::ObjectSymbolTable;
+use object::RelocationKind;
+use object::RelocationTarget;
+use object::SymbolKind;
+
+use crate::plonk_dwarf;
+
+/// Functions of the allocator shim rustc links into every program, calling
+/// the `#[global_allocator]`.
+const ALLOCATOR: [&str; 4] = [
+    "__rust_alloc",
+    "__rust_dealloc",
+    "__rust_realloc",
+    "__rust_alloc_zeroed",
+];
+
+/// A static of the binary.
+#[derive(Clone)]
+struct Static {
+    symbol: String,
+    /// Offset in the binary's TLS block for thread-locals.
+    tls: Option<u64>,
+}
+
+/// Statics of the binary by demangled path, `None` for paths naming more
+/// than one.
+#[derive(Default)]
+pub struct Statics {
+    by_path: HashMap<String, Option<Static>>,
+    /// The same by symbol.
+    by_symbol: HashMap<String, Static>,
+    /// Symbols of the binary's functions.
+    functions: HashSet<String>,
+    /// Whether std is linked into the binary rather than loaded from libstd.
+    static_std: bool,
+    /// Allocator shim functions of the binary, if it has a custom
+    /// `#[global_allocator]`.
+    allocator: Vec<String>,
+    /// Statics and std functions already warned about, see `bindings`.
+    warned: HashSet<String>,
+}
+
+impl Statics {
+    fn load(bin: &str) -> Result<Self, String> {
+        let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+        let file = object::File::parse(&*data)
+            .map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+
+        let mut statics = Self::default();
+        let mut default_allocator = false;
+        let mut shims = HashSet::new();
+        for symbol in file.symbols() {
+            let name = match symbol.name() {
+                Ok(name) if !symbol.is_undefined() => name,
+                _ => continue,
+            };
+            let tls = match symbol.kind() {
+                SymbolKind::Data => None,
+                SymbolKind::Tls => Some(symbol.address()),
+                SymbolKind::Text => {
+                    // `__rust_alloc`, or `__rustc::__rust_alloc` in newer
+                    // toolchains.
+                    let path = rustc_demangle::demangle(name).to_string();
+                    let short = path.rsplit("::").next().unwrap_or_default();
+                    if let Some(shim) = ALLOCATOR.iter().find(|shim| **shim == short) {
+                        shims.insert(*shim);
+                        statics.allocator.push(name.to_string());
+                    }
+                    // The shim calls the system allocator through it.
+                    default_allocator |= short == "__rdl_alloc";
+                    statics.static_std |= path.starts_with("std::");
+
+                    statics.functions.insert(name.to_string());
+                    continue;
+                }
+                _ => continue,
+            };
+            let path = format!("{:#}", rustc_demangle::demangle(name));
+            let found = Static {
+                symbol: name.to_string(),
+                tls,
+            };
+            statics.by_symbol.insert(name.to_string(), found.clone());
+            statics
+                .by_path
+                .entry(path)
+                .and_modify(|other| *other = None)
+                .or_insert(Some(found));
+        }
+
+        if default_allocator {
+            statics.allocator.clear();
+        } else if !statics.allocator.is_empty() {
+            plonk_log!(Debug, "{} has a custom global allocator", bin);
+            // Unused by the program, so the linker dropped it.
+            for missing in ALLOCATOR.iter().filter(|shim| !shims.contains(*shim)) {
+                plonk_log!(
+                    Warn,
+                    "{} has no `{}`, patched code calling it uses the system allocator",
+                    bin,
+                    missing
+                );
+            }
+        }
+        Ok(statics)
+    }
+}
+
+/// Find the references of `library_path` to statics and thread-locals the
+/// binary has too, so the injector can point them at the binary's instances
+/// and patched code keeps working on the program's state instead of fresh
+/// copies. Calls into dependency crates go to the binary's copy as well, so
+/// their globals, like the `log` logger or the `tracing` subscriber, are the
+/// ones the program set up. Returns them as the comma separated list the
+/// injector takes:
+///
+///   sym:<offset>:<addend>:<binary symbol>
+///   tlsmod:<offset>
+///   tlsoff:<offset>:<offset in the binary's TLS block>
+///   alloc:<allocator shim symbol>
+///
+/// The library is built against libstd, a separate copy of std with its own
+/// stdout buffer, panic hook and so on when the binary links std statically,
+/// as it does by default. References to libstd the binary has too are bound
+/// to the binary's, the rest keeps going to libstd.
+///
+/// The library allocates through libstd's shim, or its own in older
+/// toolchains, which uses the system allocator. With a custom
+/// `#[global_allocator]` in the binary the injector sends those calls to the
+/// binary's shim, so memory can cross between old and new code.
+///
+/// Only references that go through the GOT or another dynamic relocation can
+/// be moved, which rustc uses for statics reachable from outside their crate.
+/// Code reaches the others directly, so they stay separate.
+pub fn bindings(
+    cache: &mut Option<Statics>,
+    bin: &str,
+    library_path: &str,
+    package: &str,
+) -> Result<String, String> {
+    let statics = match cache {
+        Some(statics) => statics,
+        None => cache.insert(Statics::load(bin)?),
+    };
+
+    let data = std::fs::read(library_path)
+        .map_err(|err| format!("Failed to read {}: {}", library_path, err))?;
+    let file = object::File::parse(&*data)
+        .map_err(|err| format!("Failed to parse {}: {}", library_path, err))?;
+
+    let (glob_dat, jump_slot, dtpmod, dtpoff) = match file.architecture() {
+        Architecture::X86_64 => (
+            elf::R_X86_64_GLOB_DAT,
+            elf::R_X86_64_JUMP_SLOT,
+            elf::R_X86_64_DTPMOD64,
+            elf::R_X86_64_DTPOFF64,
+        ),
+        Architecture::Aarch64 => (
+            elf::R_AARCH64_GLOB_DAT,
+            elf::R_AARCH64_JUMP_SLOT,
+            elf::R_AARCH64_TLS_DTPMOD,
+            elf::R_AARCH64_TLS_DTPREL,
+        ),
+        _ => return Ok(String::new()),
+    };
+    let (relocations, symbols) = match (file.dynamic_relocations(), file.dynamic_symbol_table()) {
+        (Some(relocations), Some(symbols)) => (relocations, symbols),
+        // Mach-O binds through opcodes instead.
+        _ => return Ok(String::new()),
+    };
+
+    let own = format!("{}::", package.replace('-', "_"));
+    // Generic dependency functions instantiated with the patched crate's
+    // types run its new trait impls, the binary's instance the old ones.
+    let generic = match plonk_dwarf::instantiated_with(library_path, &package.replace('-', "_")) {
+        Ok(Some(generic)) => Some(generic),
+        Ok(None) => {
+            plonk_log!(
+                Debug,
+                "{} has no debug info, calls into dependencies stay in it",
+                library_path
+            );
+            None
+        }
+        Err(err) => {
+            plonk_log!(Debug, "{}, calls into dependencies stay in the library", err);
+            None
+        }
+    };
+    let mut entries = statics
+        .allocator
+        .iter()
+        .map(|symbol| format!("alloc:{}", symbol))
+        .collect::<Vec<_>>();
+    let mut bound = HashSet::new();
+    let (mut std_bound, mut std_left) = (0, Vec::new());
+    for (offset, relocation) in relocations {
+        let symbol = match relocation.target() {
+            RelocationTarget::Symbol(index) => match symbols.symbol_by_index(index) {
+                Ok(symbol) => symbol,
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let name = match symbol.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let path = format!("{:#}", rustc_demangle::demangle(name));
+        let found = if symbol.is_undefined() {
+            // Rust symbols come from libstd, with core and alloc, which has
+            // the same symbols as the binary's std from the same toolchain.
+            // The rest is the system's libraries, shared anyway.
+            if !statics.static_std || rustc_demangle::try_demangle(name).is_err() {
+                continue;
+            }
+            let found = match statics.by_symbol.get(name) {
+                Some(found) => Some(found.clone()),
+                None => statics.functions.contains(name).then(|| Static {
+                    symbol: name.to_string(),
+                    tls: None,
+                }),
+            };
+            match found {
+                Some(found) => {
+                    std_bound += 1;
+                    found
+                }
+                // Unused by the program, so not linked in.
+                None => {
+                    std_left.push(path);
+                    continue;
+                }
+            }
+        } else if symbol.kind() == SymbolKind::Text {
+            // The patched crate's functions are the new code. Dependencies
+            // built the same way have the same symbols in the binary, which
+            // unlike the path tell generic instances apart.
+            let involved = match &generic {
+                Some(generic) => generic.contains(name),
+                None => true,
+            };
+            if involved || path.contains(&own) || !statics.functions.contains(name) {
+                continue;
+            }
+            Static {
+                symbol: name.to_string(),
+                tls: None,
+            }
+        } else {
+            match statics.by_path.get(&path) {
+                Some(Some(found)) => found.clone(),
+                _ => continue,
+            }
+        };
+
+        let entry = match (relocation.kind(), found.tls) {
+            (RelocationKind::Elf(kind), None) if kind == glob_dat || kind == jump_slot => {
+                format!("sym:{}:0:{}", offset, found.symbol)
+            }
+            (RelocationKind::Absolute, None) => {
+                format!("sym:{}:{}:{}", offset, relocation.addend(), found.symbol)
+            }
+            (RelocationKind::Elf(kind), Some(_)) if kind == dtpmod => {
+                format!("tlsmod:{}", offset)
+            }
+            (RelocationKind::Elf(kind), Some(tls)) if kind == dtpoff => {
+                format!(
+                    "tlsoff:{}:{}",
+                    offset,
+                    tls.wrapping_add(relocation.addend() as u64)
+                )
+            }
+            _ => continue,
+        };
+        entries.push(entry);
+        bound.insert(path);
+    }
+
+    // Statics of the patched crate that keep their own copy in the library.
+    for symbol in file.symbols() {
+        if !matches!(symbol.kind(), SymbolKind::Data | SymbolKind::Tls) || symbol.is_undefined() {
+            continue;
+        }
+        let path = match symbol.name() {
+            Ok(name) => format!("{:#}", rustc_demangle::demangle(name)),
+            Err(_) => continue,
+        };
+        if !path.starts_with(&own)
+            || bound.contains(&path)
+            || !statics.by_path.contains_key(&path)
+            || !statics.warned.insert(path.clone())
+        {
+            continue;
+        }
+        plonk_log!(
+            Warn,
+            "`{}` can't be shared with the program, patched code starts with a fresh copy",
+            path
+        );
+    }
+
+    if std_bound + std_left.len() > 0 {
+        plonk_log!(
+            Debug,
+            "{} links std statically, {} references of {} to std bound to it, {} left to libstd",
+            bin,
+            std_bound,
+            library_path,
+            std_left.len()
+        );
+    }
+    // Their state, like the stdout lock or the panic hook, isn't the program's.
+    std_left.sort();
+    std_left.dedup();
+    std_left.retain(|path| statics.warned.insert(path.clone()));
+    if !std_left.is_empty() {
+        let shown = std_left
+            .iter()
+            .take(5)
+            .map(|path| format!("`{}`", path))
+            .collect::<Vec<_>>();
+        plonk_log!(
+            Warn,
+            "{} std functions the program doesn't use run libstd's copy of std, with its own \
+             state: {}{}",
+            std_left.len(),
+            shown.join(", "),
+            if std_left.len() > shown.len() { ", ..." } else { "" }
+        );
+    }
+    plonk_log!(
+        Debug,
+        "Binding {} references of {} to the binary's copies",
+        entries.len(),
+        library_path
+    );
+    Ok(entries.join(","))
+}
@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Patching functions too small to hook through their callers */
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use iced_x86::Decoder;
+use iced_x86::DecoderOptions;
+use iced_x86::FlowControl;
+use iced_x86::Instruction;
+use iced_x86::OpKind;
+use object::elf;
+use object::Architecture;
+use object::Object;
+use object::ObjectSection;
+use object::ObjectSymbol;
+use object::ObjectSymbolTable;
+use object::RelocationKind;
+use object::RelocationTarget;
+use object::SectionKind;
+use object::SymbolKind;
+
+/// A place in the binary that reaches a function, the injector points it at
+/// a trampoline jumping to the patched code.
+#[derive(Clone)]
+struct Site {
+    /// `rel32` for the displacement of an x86_64 `call` or `jmp`, `bl26`
+    /// for an aarch64 `b` or `bl` and `abs64` for a pointer.
+    kind: &'static str,
+    address: u64,
+}
+
+/// Functions of the binary and the calls to the small ones.
+pub struct Functions {
+    arch: Architecture,
+    /// Address and size by symbol.
+    by_symbol: HashMap<String, (u64, u64)>,
+    /// Symbols by address, more than one for functions that identical code
+    /// folding merged.
+    by_address: HashMap<u64, Vec<String>>,
+    /// Calls and pointers by address of the function they reach, filled
+    /// the first time a small function is patched.
+    sites: Option<HashMap<u64, Vec<Site>>>,
+}
+
+impl Functions {
+    fn load(bin: &str) -> Result<Self, String> {
+        let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+        let file = object::File::parse(&*data)
+            .map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+
+        let mut functions = Self {
+            arch: file.architecture(),
+            by_symbol: HashMap::new(),
+            by_address: HashMap::new(),
+            sites: None,
+        };
+        for symbol in file.symbols() {
+            let name = match symbol.name() {
+                Ok(name) if symbol.kind() == SymbolKind::Text && !symbol.is_undefined() => name,
+                _ => continue,
+            };
+            // Local symbols can share a name, keep the first one in both maps.
+            if let Entry::Vacant(entry) = functions.by_symbol.entry(name.to_string()) {
+                entry.insert((symbol.address(), symbol.size()));
+                functions
+                    .by_address
+                    .entry(symbol.address())
+                    .or_default()
+                    .push(name.to_string());
+            }
+        }
+        Ok(functions)
+    }
+
+    /// Bytes the injector overwrites at the start of a function to hook it,
+    /// a `jmp rel32` or a `ldr`, `br` and the address.
+    fn hook_size(&self) -> u64 {
+        match self.arch {
+            Architecture::Aarch64 => 16,
+            _ => 5,
+        }
+    }
+}
+
+/// Decide how the injector patches each `(old, new)` pair. Functions
+/// shorter than the jump the injector writes over them are patched by
+/// pointing their calls at a trampoline instead, returned as a comma
+/// separated list of `<kind>:<symbol>:<offset from the symbol>` for
+/// `PLONK_CALLS`. Pairs that can't be patched either way are removed with a
+/// warning, like functions sharing their code with others, where hooking
+/// one would patch all of them.
+pub fn plan(
+    cache: &mut Option<Functions>,
+    bin: &str,
+    pairs: &mut Vec<(String, String)>,
+) -> Result<String, String> {
+    let functions = match cache {
+        Some(functions) => functions,
+        None => cache.insert(Functions::load(bin)?),
+    };
+
+    let mut small = Vec::new();
+    pairs.retain(|(old, _)| {
+        let (address, size) = match functions.by_symbol.get(old) {
+            Some(found) => *found,
+            // Exported by the binary, `resolve_symbols()` checked it.
+            None => return true,
+        };
+        let aliases = functions
+            .by_address
+            .get(&address)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter(|alias| *alias != old)
+            .map(|alias| format!("`{:#}`", rustc_demangle::demangle(alias)))
+            .collect::<Vec<_>>();
+        if !aliases.is_empty() {
+            plonk_log!(
+                Warn,
+                "Not patching `{:#}`, it was merged with {} for having the same code and \
+                 patching it would replace those too",
+                rustc_demangle::demangle(old),
+                aliases.join(", ")
+            );
+            return false;
+        }
+        if size > 0 && size < functions.hook_size() {
+            small.push((old.clone(), address));
+        }
+        true
+    });
+    if small.is_empty() {
+        return Ok(String::new());
+    }
+
+    if functions.sites.is_none() {
+        let started = std::time::Instant::now();
+        functions.sites = Some(sites(bin, functions)?);
+        plonk_log!(Debug, "Found calls in {} in {:?}", bin, started.elapsed());
+    }
+    let sites = functions.sites.as_ref().unwrap();
+
+    let mut entries = Vec::new();
+    for (old, address) in small {
+        let found = sites.get(&address).map(Vec::as_slice).unwrap_or_default();
+        if found.is_empty() {
+            plonk_log!(
+                Warn,
+                "Not patching `{:#}`, it's too small to hook and nothing calls it directly",
+                rustc_demangle::demangle(&old)
+            );
+            pairs.retain(|(other, _)| *other != old);
+            continue;
+        }
+        plonk_log!(
+            Debug,
+            "`{:#}` is too small to hook, patching its {} callers instead",
+            rustc_demangle::demangle(&old),
+            found.len()
+        );
+        for site in found {
+            entries.push(format!(
+                "{}:{}:{}",
+                site.kind,
+                old,
+                site.address.wrapping_sub(address) as i64
+            ));
+        }
+    }
+    Ok(entries.join(","))
+}
+
+/// Every direct call, jump and relocated pointer to a function in `bin`.
+fn sites(bin: &str, functions: &Functions) -> Result<HashMap<u64, Vec<Site>>, String> {
+    let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+    let file =
+        object::File::parse(&*data).map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+
+    let mut sites: HashMap<u64, Vec<Site>> = HashMap::new();
+    let code = file
+        .sections()
+        .filter(|section| section.kind() == SectionKind::Text)
+        .filter_map(|section| Some((section.address(), section.data().ok()?)))
+        .collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    for &(start, size) in functions.by_symbol.values() {
+        if size == 0 || !seen.insert(start) {
+            continue;
+        }
+        let bytes = code.iter().find_map(|(address, data)| {
+            let offset = start.checked_sub(*address)? as usize;
+            data.get(offset..offset + size as usize)
+        });
+        let bytes = match bytes {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        match functions.arch {
+            Architecture::X86_64 => x86_64_calls(start, bytes, &mut sites),
+            Architecture::Aarch64 => aarch64_calls(start, bytes, &mut sites),
+            _ => return Ok(sites),
+        }
+    }
+
+    // Function pointers, in vtables for example, are relocated at load time
+    // in position independent binaries.
+    let relative = match functions.arch {
+        Architecture::Aarch64 => elf::R_AARCH64_RELATIVE,
+        _ => elf::R_X86_64_RELATIVE,
+    };
+    if let Some(relocations) = file.dynamic_relocations() {
+        let symbols = file.dynamic_symbol_table();
+        for (offset, relocation) in relocations {
+            let target = match (relocation.kind(), relocation.target()) {
+                (RelocationKind::Elf(kind), _) if kind == relative => relocation.addend() as u64,
+                (RelocationKind::Absolute, RelocationTarget::Symbol(index)) => {
+                    match symbols
+                        .as_ref()
+                        .map(|symbols| symbols.symbol_by_index(index))
+                    {
+                        Some(Ok(symbol)) if !symbol.is_undefined() => {
+                            symbol.address().wrapping_add(relocation.addend() as u64)
+                        }
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            if functions.by_address.contains_key(&target) {
+                sites.entry(target).or_default().push(Site {
+                    kind: "abs64",
+                    address: offset,
+                });
+            }
+        }
+    }
+    Ok(sites)
+}
+
+fn x86_64_calls(start: u64, bytes: &[u8], sites: &mut HashMap<u64, Vec<Site>>) {
+    let mut decoder = Decoder::with_ip(64, bytes, start, DecoderOptions::NONE);
+    let mut instruction = Instruction::default();
+    while decoder.can_decode() {
+        decoder.decode_out(&mut instruction);
+        let branch = matches!(
+            instruction.flow_control(),
+            FlowControl::Call | FlowControl::UnconditionalBranch | FlowControl::ConditionalBranch
+        );
+        // Short jumps only reach within their function.
+        if !branch || instruction.op0_kind() != OpKind::NearBranch64 || instruction.len() < 5 {
+            continue;
+        }
+        // The displacement ends the instruction.
+        sites
+            .entry(instruction.near_branch_target())
+            .or_default()
+            .push(Site {
+                kind: "rel32",
+                address: instruction.next_ip() - 4,
+            });
+    }
+}
+
+fn aarch64_calls(start: u64, bytes: &[u8], sites: &mut HashMap<u64, Vec<Site>>) {
+    for (index, word) in bytes.chunks_exact(4).enumerate() {
+        let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        // `b` and `bl`, with a signed 26 bit offset in instructions.
+        if word & 0x7c00_0000 != 0x1400_0000 {
+            continue;
+        }
+        let address = start + index as u64 * 4;
+        let offset = (((word & 0x03ff_ffff) << 6) as i32 >> 4) as i64;
+        sites
+            .entry(address.wrapping_add(offset as u64))
+            .or_default()
+            .push(Site {
+                kind: "bl26",
+                address,
+            });
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Child process supervision for Unix */
+
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Process group of the running child, 0 when there is none.
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+
+/// Whether the terminal was handed to the child's process group.
+static TERMINAL: AtomicBool = AtomicBool::new(false);
+
+/// The last signal forwarded to the child, 0 for none.
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+const FORWARDED_SIGNALS: [libc::c_int; 4] =
+    [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT];
+
+extern "C" fn forward(sig: libc::c_int) {
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    unsafe {
+        if pgid > 0 {
+            RECEIVED.store(sig, Ordering::SeqCst);
+            libc::kill(-pgid, sig);
+        } else {
+            // Nothing to forward to, behave as if we never installed a handler.
+            libc::signal(sig, libc::SIG_DFL);
+            libc::raise(sig);
+        }
+    }
+}
+
+/// Spawn `command` in its own process group and forward the termination
+/// signals plonk receives to that group.
+///
+/// If `terminal` is set and plonk owns the terminal, the child's group becomes
+/// the foreground group so Ctrl-C and job control reach the program directly.
+pub fn spawn(command: &mut Command, terminal: bool) -> io::Result<Child> {
+    own_group(command);
+
+    let child = command.spawn()?;
+    let pgid = child.id() as libc::pid_t;
+    CHILD_PGID.store(pgid, Ordering::SeqCst);
+
+    unsafe {
+        for sig in FORWARDED_SIGNALS {
+            libc::signal(sig, forward as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+
+        let foreground = terminal
+            && libc::isatty(libc::STDIN_FILENO) == 1
+            && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp();
+        if foreground && libc::tcsetpgrp(libc::STDIN_FILENO, pgid) == 0 {
+            TERMINAL.store(true, Ordering::SeqCst);
+        }
+    }
+
+    Ok(child)
+}
+
+/// Run `command` in a process group of its own, so that it can be killed
+/// along with everything it spawns.
+pub fn own_group(command: &mut Command) {
+    command.process_group(0);
+
+    #[cfg(target_os = "linux")]
+    unsafe {
+        command.pre_exec(|| {
+            // Don't outlive plonk, even if it gets SIGKILL'd.
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+            Ok(())
+        });
+    }
+}
+
+/// Kill the process group of a child spawned with `own_group`.
+pub fn kill_group(child: &mut Child) -> io::Result<ExitStatus> {
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    child.wait()
+}
+
+/// Wait for the child, take the terminal back and terminate whatever is left
+/// of its process group.
+pub fn wait(child: &mut Child) -> io::Result<ExitStatus> {
+    let status = child.wait();
+    release();
+    status
+}
+
+/// Like `wait`, but returns `None` right away if the child is still running.
+pub fn try_wait(child: &mut Child) -> io::Result<Option<ExitStatus>> {
+    let status = child.try_wait()?;
+    if status.is_some() {
+        release();
+    }
+    Ok(status)
+}
+
+/// Ask the child's process group to stop with `sig`, and kill it if it is
+/// still around after `timeout`.
+pub fn stop(child: &mut Child, sig: libc::c_int, timeout: Duration) -> io::Result<ExitStatus> {
+    let pgid = CHILD_PGID.load(Ordering::SeqCst);
+    unsafe { libc::kill(-pgid, sig) };
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = try_wait(child)? {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    wait(child)
+}
+
+/// Parse a signal name (`TERM`, `SIGINT`, `sigterm`) or number.
+pub fn parse_signal(name: &str) -> Option<libc::c_int> {
+    #[cfg(target_os = "linux")]
+    let max = libc::SIGRTMAX();
+    // NSIG - 1 on macOS and the BSDs.
+    #[cfg(not(target_os = "linux"))]
+    let max = 31;
+    // 0 and negative numbers would have `kill` probe or signal other groups.
+    if let Ok(sig) = name.parse::<libc::c_int>() {
+        return (1..=max).contains(&sig).then_some(sig);
+    }
+
+    let name = name.to_ascii_uppercase();
+    let sig = match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(sig)
+}
+
+/// Take the terminal back and terminate the child's process group, if any.
+pub fn release() {
+    let pgid = CHILD_PGID.swap(0, Ordering::SeqCst);
+
+    unsafe {
+        if TERMINAL.swap(false, Ordering::SeqCst) {
+            // We are a background group now, so block SIGTTOU while reclaiming.
+            let mut set = std::mem::zeroed();
+            let mut old = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTTOU);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut old);
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+            libc::pthread_sigmask(libc::SIG_SETMASK, &old, std::ptr::null_mut());
+        }
+
+        if pgid > 0 {
+            libc::kill(-pgid, libc::SIGTERM);
+        }
+    }
+}
+
+/// The signal plonk got and forwarded to the child, as the status to exit
+/// with once the child is gone.
+pub fn received() -> Option<ExitStatus> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(ExitStatus::from_raw(sig)),
+    }
+}
+
+/// Exit plonk the same way the child exited.
+pub fn exit_with(status: ExitStatus) -> ! {
+    if let Some(sig) = status.signal() {
+        if matches!(sig, libc::SIGINT | libc::SIGTERM | libc::SIGHUP) {
+            unsafe {
+                libc::signal(sig, libc::SIG_DFL);
+                libc::raise(sig);
+            }
+        }
+        std::process::exit(128 + sig);
+    }
+
+    std::process::exit(status.code().unwrap_or(1))
+}
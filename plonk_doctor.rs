@@ -0,0 +1,361 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* `cargo plonk doctor`, checking everything plonk needs before it fails halfway */
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use object::Object;
+use object::ObjectSegment;
+
+use crate::build;
+use crate::command;
+use crate::find_bin;
+use crate::find_symbol;
+use crate::function_symbols;
+use crate::inject_dylib;
+use crate::plonk_injector;
+use crate::plonk_missing;
+use crate::plonk_profile;
+use crate::plonk_toolchain;
+use crate::private_temp_dir;
+use crate::rustc;
+use crate::rustc_libdir;
+use crate::Options;
+
+/// Passed and failed checks, printed as they run.
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    /// Print the outcome of `what`, returns whether it passed.
+    fn check(&mut self, what: &str, result: Result<String, String>) -> bool {
+        match result {
+            Ok(detail) => {
+                println!("  ok    {}: {}", what, detail);
+                true
+            }
+            Err(err) => {
+                println!("  FAIL  {}: {}", what, err);
+                self.failed += 1;
+                false
+            }
+        }
+    }
+
+    fn skip(&self, what: &str, reason: &str) {
+        println!("  skip  {}: {}", what, reason);
+    }
+}
+
+/// Check the tools, the injector, the binary and the library a `run` of the
+/// same options needs, printing a line for each. Builds the library like
+/// `build` does. Returns whether everything passed.
+pub fn doctor(pargs: &mut Options) -> bool {
+    let mut report = Report::default();
+
+    println!("tools");
+    let cargo = report.check("cargo", version(Command::new("cargo")));
+    let rustc_found = report.check("rustc", version(rustc()));
+    let nm = report.check("nm", version(Command::new("nm")));
+    if rustc_found {
+        report.check("sysroot", libstd());
+    } else {
+        report.skip("sysroot", "needs rustc");
+    }
+
+    println!("injector");
+    let injector = report.check("library", injector());
+    if injector {
+        report.check("preload", preload(pargs));
+    } else {
+        report.skip("preload", "needs the injector");
+    }
+
+    println!("binary");
+    let found = find_bin(pargs);
+    let bin = report.check(
+        "built",
+        match &found {
+            Some((package, bin)) => built(package, bin, &pargs.profile),
+            None => Err("no binary to run, pick one with `-b`".into()),
+        },
+    );
+    let (bin_package, bin) = match found {
+        Some(found) if bin => found,
+        _ => {
+            report.skip("symbols", "needs the binary");
+            return summary(&report);
+        }
+    };
+    let symbols = nm && report.check("symbols", symbol_table(&bin));
+    report.check("loader", loader(&bin));
+    report.check(
+        "profile",
+        plonk_profile::check(&bin, &bin_package, &pargs.package, &pargs.profile).map(|warnings| {
+            if warnings.is_empty() {
+                "patchable".into()
+            } else {
+                warnings.join(", ")
+            }
+        }),
+    );
+
+    println!("library");
+    if !cargo {
+        report.skip("built", "needs cargo");
+        return summary(&report);
+    }
+    pargs._internal_meta = true;
+    let library_path = match build(pargs) {
+        Some(artifact) => artifact.filenames[0].to_string(),
+        None => {
+            report.check(
+                "built",
+                Err(format!("building {} failed, see above", pargs.package)),
+            );
+            return summary(&report);
+        }
+    };
+    report.check("built", Ok(library_path.clone()));
+    if rustc_found {
+        report.check(
+            "toolchain",
+            plonk_toolchain::check(&mut None, &bin, &library_path)
+                .map(|()| "same rustc and std as the binary".into()),
+        );
+    } else {
+        report.skip("toolchain", "needs rustc");
+    }
+
+    if pargs.symbols.is_empty() {
+        report.skip("-s", "no symbols given");
+    } else if !symbols {
+        report.skip("-s", "needs nm and the binary's symbol table");
+    } else {
+        for symbol in &pargs.symbols {
+            let in_bin = find_symbol(&bin, &pargs.package, symbol).is_some();
+            let in_lib = find_symbol(&library_path, &pargs.package, symbol).is_some();
+            let found = report.check(
+                &format!("-s {}", symbol),
+                match (in_bin, in_lib) {
+                    (true, true) => Ok("in the binary and the library".into()),
+                    (false, _) => Err(format!("not in {}, see below", bin)),
+                    (true, false) => Err(format!("not in {}, see below", library_path)),
+                },
+            );
+            if !found {
+                plonk_missing::explain(&pargs.package, symbol, &bin, Some(&library_path));
+            }
+        }
+    }
+
+    summary(&report)
+}
+
+fn summary(report: &Report) -> bool {
+    match report.failed {
+        0 => println!("Everything plonk needs is in place"),
+        1 => println!("1 check failed"),
+        failed => println!("{} checks failed", failed),
+    }
+    report.failed == 0
+}
+
+/// First line of `<tool> --version`.
+fn version(mut tool: Command) -> Result<String, String> {
+    let name = tool.get_program().to_string_lossy().into_owned();
+    match tool.arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            Ok(stdout.lines().next().unwrap_or_default().to_string())
+        }
+        Ok(output) => Err(format!(
+            "`{} --version` failed with {}",
+            name, output.status
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(match name.as_str() {
+            "nm" => {
+                "not in PATH, install binutils, or the Xcode command line tools on macOS".into()
+            }
+            _ => format!("{} isn't in PATH, install it with rustup", name),
+        }),
+        Err(err) => Err(format!("Failed to spawn {}: {}", name, err)),
+    }
+}
+
+/// The toolchain's libstd the library links.
+fn libstd() -> Result<String, String> {
+    let libdir = rustc_libdir();
+    let entries = std::fs::read_dir(&libdir).map_err(|err| {
+        format!(
+            "{} from `rustc --print target-libdir`: {}",
+            libdir.display(),
+            err
+        )
+    })?;
+    entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with("libstd-") && name.ends_with(std::env::consts::DLL_SUFFIX))
+        .map(|name| libdir.join(name).display().to_string())
+        .ok_or_else(|| {
+            format!(
+                "no shared libstd in {}, reinstall the toolchain with rustup",
+                libdir.display()
+            )
+        })
+}
+
+/// The injector embedded in plonk, extracted and loaded into plonk itself.
+/// It does nothing without `PLONK_PROTOCOL`.
+fn injector() -> Result<String, String> {
+    let path = plonk_injector::extract()?;
+    let c_path = CString::new(path.as_str()).map_err(|err| err.to_string())?;
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        let err = unsafe { libc::dlerror() };
+        let err = if err.is_null() {
+            "unknown error".into()
+        } else {
+            unsafe { CStr::from_ptr(err) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        return Err(format!(
+            "{} doesn't load: {}, reinstall plonk with `cargo install cargo-plonk`",
+            path, err
+        ));
+    }
+    unsafe { libc::dlclose(handle) };
+    Ok(path)
+}
+
+/// Run plonk itself with the injector preloaded the way `run` starts the
+/// binary, and wait for the timings it writes at startup.
+fn preload(pargs: &Options) -> Result<String, String> {
+    let exe = std::env::current_exe().map_err(|err| err.to_string())?;
+    // Where no one else can put a report for the injector.
+    let report = private_temp_dir()?.join(format!("{}.doctor", std::process::id()));
+    let _ = std::fs::remove_file(&report);
+
+    let mut probe = command(pargs, &exe.to_string_lossy());
+    probe.env("PLONK_TIMINGS", &report).arg("--help");
+    let output = probe
+        .output()
+        .map_err(|err| format!("Failed to spawn {}: {}", exe.display(), err))?;
+    // `<load us>\t<patch us>\n`, as only the injector writes it.
+    let written = std::fs::read_to_string(&report).unwrap_or_default();
+    let _ = std::fs::remove_file(&report);
+    let loaded = written.strip_suffix('\n').is_some_and(|report| {
+        let fields = report.split('\t').collect::<Vec<_>>();
+        fields.len() == 2 && fields.iter().all(|field| field.parse::<u64>().is_ok())
+    });
+    if loaded {
+        return Ok("the injector runs in new processes".into());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let reason = match stderr.lines().find(|line| line.contains(inject_dylib())) {
+        Some(line) => line.trim().to_string(),
+        None if cfg!(target_os = "macos") => {
+            "dyld ignored DYLD_INSERT_LIBRARIES, System Integrity Protection strips it for \
+             programs under /usr and /bin and for ones signed with the hardened runtime"
+                .into()
+        }
+        None => "the dynamic loader ignored LD_PRELOAD, check for a sandbox or security \
+                 module blocking it"
+            .into(),
+    };
+    Err(format!(
+        "the injector didn't load into {}: {}",
+        exe.display(),
+        reason
+    ))
+}
+
+/// Whether the binary was built for the profile plonk builds the library
+/// with.
+fn built(package: &str, bin: &str, profile: &str) -> Result<String, String> {
+    if Path::new(bin).exists() {
+        return Ok(bin.into());
+    }
+    Err(format!(
+        "{} doesn't exist, run `cargo build -p {}{}`",
+        bin,
+        package,
+        plonk_profile::args(profile)
+    ))
+}
+
+/// Functions have to be found by name.
+fn symbol_table(bin: &str) -> Result<String, String> {
+    match function_symbols(bin).len() {
+        0 => Err(format!(
+            "{} is stripped, set `strip = false` for its profile and rebuild it",
+            bin
+        )),
+        functions => Ok(format!("{} functions", functions)),
+    }
+}
+
+/// Whether the dynamic loader will honor the injector for `bin`.
+fn loader(bin: &str) -> Result<String, String> {
+    let mode = std::fs::metadata(bin)
+        .map_err(|err| format!("Failed to read {}: {}", bin, err))?
+        .permissions()
+        .mode();
+    if mode & 0o6000 != 0 {
+        return Err(format!(
+            "{} is setuid or setgid, the loader ignores the injector for it, `chmod u-s,g-s` it",
+            bin
+        ));
+    }
+
+    let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+    let file =
+        object::File::parse(&*data).map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+    match file.format() {
+        object::BinaryFormat::Elf if file.section_by_name(".interp").is_none() => Err(format!(
+            "{} is statically linked, so nothing loads the injector. Build it without \
+             `-C target-feature=+crt-static` or for a `-gnu` target",
+            bin
+        )),
+        object::BinaryFormat::MachO
+            if file
+                .segments()
+                .any(|segment| segment.name() == Ok(Some("__RESTRICT"))) =>
+        {
+            Err(format!(
+                "{} has a __RESTRICT segment, dyld ignores DYLD_INSERT_LIBRARIES for it",
+                bin
+            ))
+        }
+        _ => Ok("loads the injector".into()),
+    }
+}
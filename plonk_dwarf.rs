@@ -0,0 +1,804 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Function signatures and type layouts from DWARF debug info */
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use gimli::AttributeValue;
+use gimli::EndianSlice;
+use gimli::EntriesTreeNode;
+use gimli::RunTimeEndian;
+use gimli::UnitOffset;
+use object::Object;
+use object::ObjectSection;
+
+type Slice<'a> = EndianSlice<'a, RunTimeEndian>;
+type Unit<'a> = gimli::Unit<Slice<'a>>;
+
+/// How deep to follow types referring to other types.
+const MAX_DEPTH: usize = 32;
+
+/// Parameter and return types of a function, and the layouts of the types
+/// it can reach.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Signature {
+    params: Vec<String>,
+    ret: Option<String>,
+    /// Structs, enums and unions by name. Enum variants are named
+    /// `<enum>::<variant>`.
+    layouts: BTreeMap<String, Layout>,
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+struct Layout {
+    size: Option<u64>,
+    fields: Vec<Field>,
+    /// Variants and their discriminants.
+    variants: Vec<(String, Option<u64>)>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+struct Field {
+    name: String,
+    offset: Option<u64>,
+    ty: String,
+}
+
+impl std::fmt::Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "fn({})", self.params.join(", "))?;
+        if let Some(ret) = &self.ret {
+            write!(f, " -> {}", ret)?;
+        }
+        Ok(())
+    }
+}
+
+/// Make sure every `(old, new)` symbol pair takes and returns the same types,
+/// and that the types it uses kept their layout, since callers and live
+/// objects in the binary still use the old ones. Functions without debug
+/// info, e.g. on macOS where it stays in the object files, are let through.
+pub fn check_signatures(
+    cache: &mut HashMap<String, Option<Signature>>,
+    bin: &str,
+    library_path: &str,
+    pairs: &[(String, String)],
+) -> Result<(), String> {
+    let missing = pairs
+        .iter()
+        .map(|(old, _)| old.as_str())
+        .filter(|old| !cache.contains_key(*old))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let mut found = signatures(bin, &missing)?;
+        for old in missing {
+            cache.insert(old.to_string(), found.remove(old));
+        }
+    }
+
+    let news = pairs
+        .iter()
+        .map(|(_, new)| new.as_str())
+        .collect::<Vec<_>>();
+    let found = signatures(library_path, &news)?;
+
+    for (old, new) in pairs {
+        let (old_sig, new_sig) = match (&cache[old], found.get(new)) {
+            (Some(old_sig), Some(new_sig)) => (old_sig, new_sig),
+            _ => {
+                plonk_log!(
+                    Debug,
+                    "No debug info for `{}`, not checking its signature",
+                    old
+                );
+                continue;
+            }
+        };
+        if old_sig == new_sig {
+            continue;
+        }
+
+        let name = format!("{:#}", rustc_demangle::demangle(old));
+        let (before, after) = (old_sig.to_string(), new_sig.to_string());
+        if before != after {
+            return Err(format!(
+                "signature of `{}` changed from `{}` to `{}`",
+                name, before, after
+            ));
+        }
+
+        // Types only one side uses can't have live objects of the other.
+        for (ty, old_layout) in &old_sig.layouts {
+            match new_sig.layouts.get(ty) {
+                Some(new_layout) if new_layout != old_layout => {
+                    return Err(format!(
+                        "layout of `{}` used by `{}` changed, {}",
+                        ty,
+                        name,
+                        describe(old_layout, new_layout)
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A call the binary inlined, so patching the called function's own code
+/// doesn't reach it.
+#[derive(Clone)]
+pub struct Inlined {
+    /// Symbol of the inlined function.
+    pub callee: String,
+    /// Symbol of the function it was inlined into.
+    pub caller: String,
+    /// `src/main.rs:12` of the call, when recorded.
+    pub location: Option<String>,
+}
+
+/// Every place `bin` inlined one of `symbols` into a caller. Looked up once
+/// per symbol, `cache` keeps the result until the binary changes.
+pub fn inlined(
+    cache: &mut HashMap<String, Vec<Inlined>>,
+    bin: &str,
+    symbols: &[&str],
+) -> Result<Vec<Inlined>, String> {
+    let missing = symbols
+        .iter()
+        .copied()
+        .filter(|symbol| !cache.contains_key(*symbol))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let found = load(bin, |dwarf| {
+            inlined_calls(dwarf, |callee| missing.contains(&callee))
+        })?;
+        for symbol in missing {
+            cache.insert(symbol.to_string(), Vec::new());
+        }
+        for call in found {
+            cache.get_mut(&call.callee).unwrap().push(call);
+        }
+    }
+
+    Ok(symbols
+        .iter()
+        .flat_map(|symbol| cache[*symbol].iter().cloned())
+        .collect())
+}
+
+/// Every inlined call in `path` to a function whose symbol demangles to
+/// something containing `function`, like `example_lib::say_hello`.
+pub fn inlined_path(path: &str, function: &str) -> Result<Vec<Inlined>, String> {
+    load(path, |dwarf| {
+        inlined_calls(dwarf, |callee| {
+            rustc_demangle::demangle(callee)
+                .to_string()
+                .contains(function)
+        })
+    })
+}
+
+fn inlined_calls(
+    dwarf: &gimli::Dwarf<Slice>,
+    wanted: impl Fn(&str) -> bool,
+) -> gimli::Result<Vec<Inlined>> {
+    let mut found = Vec::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        // Functions enclosing the current entry, with their depth.
+        let mut callers: Vec<(isize, Option<String>)> = Vec::new();
+        // Depth of the abstract instance tree the current entry is in.
+        let mut abstract_at: Option<isize> = None;
+        let mut depth = 0;
+        let mut entries = unit.entries();
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            while callers.last().is_some_and(|(at, _)| *at >= depth) {
+                callers.pop();
+            }
+            if abstract_at.is_some_and(|at| at >= depth) {
+                abstract_at = None;
+            }
+            if abstract_at.is_some() {
+                continue;
+            }
+
+            match entry.tag() {
+                // What inlined copies of a function refer to, without code
+                // of its own. Its calls are in the copies.
+                gimli::DW_TAG_subprogram
+                    if entry.attr(gimli::DW_AT_inline)?.is_some()
+                        && entry.attr(gimli::DW_AT_low_pc)?.is_none()
+                        && entry.attr(gimli::DW_AT_ranges)?.is_none() =>
+                {
+                    abstract_at = Some(depth);
+                }
+                gimli::DW_TAG_subprogram => {
+                    callers.push((depth, symbol_of(dwarf, &unit, entry)?));
+                }
+                gimli::DW_TAG_inlined_subroutine => {
+                    let callee = match symbol_of(dwarf, &unit, entry)? {
+                        Some(callee) if wanted(&callee) => callee,
+                        _ => continue,
+                    };
+                    // Inlined into a function that was itself inlined, the
+                    // outermost one is the one left in the binary.
+                    let caller = callers
+                        .iter()
+                        .find_map(|(_, caller)| caller.clone())
+                        .unwrap_or_else(|| "?".into());
+                    found.push(Inlined {
+                        callee,
+                        caller,
+                        location: call_location(dwarf, &unit, entry)?,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Symbols of the generic functions in `path` instantiated with a type of
+/// the crate `krate`, like `alloc::vec::Vec<example_lib::Foo>::push`. Legacy
+/// mangled symbols don't say, the debug info does. `None` without it.
+pub fn instantiated_with(path: &str, krate: &str) -> Result<Option<HashSet<String>>, String> {
+    let own = format!("{}::", krate);
+    load(path, |dwarf| generic_instances(dwarf, &own))
+}
+
+fn generic_instances(
+    dwarf: &gimli::Dwarf<Slice>,
+    own: &str,
+) -> gimli::Result<Option<HashSet<String>>> {
+    let mut found = HashSet::new();
+    let mut any = false;
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        any = true;
+        let unit = dwarf.unit(header)?;
+        let paths = namespaces(dwarf, &unit)?;
+        // The function the current entry is in, with its depth.
+        let mut function: Option<(isize, Option<String>)> = None;
+        let mut depth = 0;
+        let mut entries = unit.entries();
+        while let Some((delta, entry)) = entries.next_dfs()? {
+            depth += delta;
+            if function.as_ref().is_some_and(|(at, _)| *at >= depth) {
+                function = None;
+            }
+
+            match entry.tag() {
+                gimli::DW_TAG_subprogram => {
+                    function = Some((depth, symbol_of(dwarf, &unit, entry)?));
+                }
+                // Every generic parameter of the instance, its impl's too.
+                gimli::DW_TAG_template_type_parameter => {
+                    let symbol = match &function {
+                        Some((at, Some(symbol))) if *at == depth - 1 => symbol,
+                        _ => continue,
+                    };
+                    let ty = match type_ref(entry)? {
+                        Some(ty) => type_name(dwarf, &unit, &paths, ty, 0)?,
+                        None => continue,
+                    };
+                    // Not the end of another crate's name.
+                    let mentions = ty.match_indices(own).any(|(start, _)| {
+                        !ty[..start].ends_with(|c: char| c.is_alphanumeric() || c == '_')
+                    });
+                    if mentions {
+                        found.insert(symbol.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(any.then_some(found))
+}
+
+/// Symbol of a function entry, following an inlined or out-of-line instance
+/// to its abstract declaration.
+fn symbol_of(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    entry: &gimli::DebuggingInformationEntry<Slice>,
+) -> gimli::Result<Option<String>> {
+    let mut entry = entry.clone();
+    for _ in 0..2 {
+        let name = match entry.attr_value(gimli::DW_AT_linkage_name)? {
+            Some(name) => Some(name),
+            None => entry.attr_value(gimli::DW_AT_name)?,
+        };
+        if let Some(name) = name {
+            return Ok(Some(
+                dwarf
+                    .attr_string(unit, name)?
+                    .to_string_lossy()
+                    .into_owned(),
+            ));
+        }
+        let origin = match entry.attr_value(gimli::DW_AT_abstract_origin)? {
+            Some(AttributeValue::UnitRef(offset)) => offset,
+            _ => match entry.attr_value(gimli::DW_AT_specification)? {
+                Some(AttributeValue::UnitRef(offset)) => offset,
+                _ => return Ok(None),
+            },
+        };
+        entry = unit.entry(origin)?;
+    }
+    Ok(None)
+}
+
+/// `file:line` an inlined call was made from.
+fn call_location(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    entry: &gimli::DebuggingInformationEntry<Slice>,
+) -> gimli::Result<Option<String>> {
+    let (file, line) = match (
+        entry.attr_value(gimli::DW_AT_call_file)?,
+        number(entry.attr_value(gimli::DW_AT_call_line)?),
+    ) {
+        (Some(AttributeValue::FileIndex(file)), Some(line)) => (file, line),
+        _ => return Ok(None),
+    };
+    let header = match &unit.line_program {
+        Some(program) => program.header(),
+        None => return Ok(None),
+    };
+    let file = match header.file(file) {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+
+    let mut path = String::new();
+    if let Some(dir) = file.directory(header) {
+        path = dwarf.attr_string(unit, dir)?.to_string_lossy().into_owned();
+        if !path.is_empty() {
+            path.push('/');
+        }
+    }
+    path.push_str(&dwarf.attr_string(unit, file.path_name())?.to_string_lossy());
+    Ok(Some(format!("{}:{}", path, line)))
+}
+
+/// What changed between two layouts of a type.
+fn describe(old: &Layout, new: &Layout) -> String {
+    let bytes = |size: Option<u64>| match size {
+        Some(size) => format!("{} bytes", size),
+        None => "unsized".into(),
+    };
+    if old.size != new.size {
+        return format!("it went from {} to {}", bytes(old.size), bytes(new.size));
+    }
+
+    for field in &old.fields {
+        let moved = match new.fields.iter().find(|new| new.name == field.name) {
+            Some(moved) => moved,
+            None => return format!("field `{}` was removed", field.name),
+        };
+        if moved.ty != field.ty {
+            return format!(
+                "field `{}` changed from `{}` to `{}`",
+                field.name, field.ty, moved.ty
+            );
+        }
+        if moved.offset != field.offset {
+            return format!(
+                "field `{}` moved from offset {:?} to {:?}",
+                field.name, field.offset, moved.offset
+            );
+        }
+    }
+    if let Some(added) = new.fields.iter().find(|new| !old.fields.contains(new)) {
+        return format!("field `{}` was added", added.name);
+    }
+
+    for (variant, discriminant) in &old.variants {
+        match new.variants.iter().find(|(new, _)| new == variant) {
+            Some((_, new)) if new != discriminant => {
+                return format!(
+                    "discriminant of `{}` went from {:?} to {:?}",
+                    variant, discriminant, new
+                );
+            }
+            Some(_) => {}
+            None => return format!("variant `{}` was removed", variant),
+        }
+    }
+    "variants were added".into()
+}
+
+/// Signatures of `symbols` in the DWARF of `path`, keyed by symbol.
+fn signatures(path: &str, symbols: &[&str]) -> Result<HashMap<String, Signature>, String> {
+    load(path, |dwarf| find(dwarf, symbols))
+}
+
+/// Run `read` over the DWARF of `path`.
+fn load<R>(
+    path: &str,
+    read: impl FnOnce(&gimli::Dwarf<Slice>) -> gimli::Result<R>,
+) -> Result<R, String> {
+    let data = std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let file =
+        object::File::parse(&*data).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+
+    let load = |id: gimli::SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(file
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    };
+    let sections = gimli::Dwarf::load(load).map_err(|err| err.to_string())?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    read(&dwarf).map_err(|err| format!("Failed to read debug info of {}: {}", path, err))
+}
+
+fn find(
+    dwarf: &gimli::Dwarf<Slice>,
+    symbols: &[&str],
+) -> gimli::Result<HashMap<String, Signature>> {
+    let mut found = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut paths = None;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_subprogram {
+                continue;
+            }
+            // Declarations have no parameters to compare.
+            if entry.attr_value(gimli::DW_AT_declaration)?.is_some() {
+                continue;
+            }
+            // Mangled functions have a linkage name, `#[no_mangle]` ones
+            // only a name.
+            let name = match entry.attr_value(gimli::DW_AT_linkage_name)? {
+                Some(value) => value,
+                None => match entry.attr_value(gimli::DW_AT_name)? {
+                    Some(value) => value,
+                    None => continue,
+                },
+            };
+            let name = dwarf.attr_string(&unit, name)?.to_string_lossy();
+            if !symbols.contains(&&*name) || found.contains_key(&*name) {
+                continue;
+            }
+
+            if paths.is_none() {
+                paths = Some(namespaces(dwarf, &unit)?);
+            }
+            let signature = signature(dwarf, &unit, paths.as_ref().unwrap(), entry.offset())?;
+            found.insert(name.into_owned(), signature);
+        }
+
+        if found.len() == symbols.len() {
+            break;
+        }
+    }
+    Ok(found)
+}
+
+/// Namespace of every struct, enum and union in `unit`, like `example_lib::config`
+/// for `example_lib::config::Config`. Types are named without it.
+fn namespaces(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+) -> gimli::Result<HashMap<UnitOffset, String>> {
+    let mut paths = HashMap::new();
+    // Namespaces enclosing the current entry, with their depth.
+    let mut scopes: Vec<(isize, String)> = Vec::new();
+    let mut depth = 0;
+    let mut entries = unit.entries();
+    while let Some((delta, entry)) = entries.next_dfs()? {
+        depth += delta;
+        while scopes.last().is_some_and(|(at, _)| *at >= depth) {
+            scopes.pop();
+        }
+
+        match entry.tag() {
+            gimli::DW_TAG_namespace => {
+                let name = name_of(dwarf, unit, entry)?;
+                let path = match scopes.last() {
+                    Some((_, parent)) => format!("{}::{}", parent, name),
+                    None => name,
+                };
+                scopes.push((depth, path));
+            }
+            gimli::DW_TAG_structure_type
+            | gimli::DW_TAG_union_type
+            | gimli::DW_TAG_enumeration_type => {
+                if let Some((_, path)) = scopes.last() {
+                    paths.insert(entry.offset(), path.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(paths)
+}
+
+/// Layouts found while walking types, see `collect`.
+#[derive(Default)]
+struct Collected {
+    layouts: BTreeMap<String, Layout>,
+    seen: HashSet<UnitOffset>,
+}
+
+fn signature(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    paths: &HashMap<UnitOffset, String>,
+    offset: UnitOffset,
+) -> gimli::Result<Signature> {
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let root = tree.root()?;
+
+    let mut used = Vec::new();
+    let ret = match type_ref(root.entry())? {
+        Some(offset) => {
+            used.push(offset);
+            Some(type_name(dwarf, unit, paths, offset, 0)?)
+        }
+        None => None,
+    };
+
+    let mut params = Vec::new();
+    let mut children = root.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        if entry.tag() == gimli::DW_TAG_formal_parameter {
+            params.push(match type_ref(entry)? {
+                Some(offset) => type_name(dwarf, unit, paths, offset, 0)?,
+                None => "?".into(),
+            });
+        }
+        locals(child, &mut used)?;
+    }
+
+    let mut collected = Collected::default();
+    for offset in used {
+        collect(dwarf, unit, paths, offset, "", 0, &mut collected)?;
+    }
+    Ok(Signature {
+        params,
+        ret,
+        layouts: collected.layouts,
+    })
+}
+
+/// Types of the parameters and variables under `node`.
+fn locals(node: EntriesTreeNode<Slice>, used: &mut Vec<UnitOffset>) -> gimli::Result<()> {
+    let entry = node.entry();
+    match entry.tag() {
+        gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+            used.extend(type_ref(entry)?);
+        }
+        gimli::DW_TAG_lexical_block => {
+            let mut children = node.children();
+            while let Some(child) = children.next()? {
+                locals(child, used)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Record the layout of the type at `offset` and every type it refers to,
+/// by path. `scope` names the enum of variant types.
+fn collect(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    paths: &HashMap<UnitOffset, String>,
+    offset: UnitOffset,
+    scope: &str,
+    depth: usize,
+    collected: &mut Collected,
+) -> gimli::Result<()> {
+    if depth == MAX_DEPTH || !collected.seen.insert(offset) {
+        return Ok(());
+    }
+
+    let mut tree = unit.entries_tree(Some(offset))?;
+    let root = tree.root()?;
+    let entry = root.entry();
+    let tag = entry.tag();
+    let name = match entry.attr_value(gimli::DW_AT_name)? {
+        Some(name) => Some(
+            dwarf
+                .attr_string(unit, name)?
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        None => None,
+    };
+    let name = match name {
+        Some(name) if !scope.is_empty() => Some(format!("{}::{}", scope, name)),
+        Some(name) => Some(qualified(paths, offset, name)),
+        None => None,
+    };
+
+    let mut layout = Layout {
+        size: number(entry.attr_value(gimli::DW_AT_byte_size)?),
+        ..Default::default()
+    };
+    let mut refs = type_ref(entry)?.into_iter().collect::<Vec<_>>();
+    // Types of enum variants.
+    let mut variants = Vec::new();
+
+    let mut children = root.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match entry.tag() {
+            gimli::DW_TAG_member => {
+                layout.fields.push(field(dwarf, unit, paths, entry)?);
+                refs.extend(type_ref(entry)?);
+            }
+            gimli::DW_TAG_enumerator => {
+                let value = number(entry.attr_value(gimli::DW_AT_const_value)?);
+                layout.variants.push((name_of(dwarf, unit, entry)?, value));
+            }
+            // Rust enums, a variant per discriminant value.
+            gimli::DW_TAG_variant_part => {
+                let mut parts = child.children();
+                while let Some(part) = parts.next()? {
+                    // The discriminant itself.
+                    if part.entry().tag() == gimli::DW_TAG_member {
+                        layout.fields.push(field(dwarf, unit, paths, part.entry())?);
+                        continue;
+                    }
+                    if part.entry().tag() != gimli::DW_TAG_variant {
+                        continue;
+                    }
+                    let value = number(part.entry().attr_value(gimli::DW_AT_discr_value)?);
+                    let mut members = part.children();
+                    while let Some(member) = members.next()? {
+                        let entry = member.entry();
+                        if entry.tag() != gimli::DW_TAG_member {
+                            continue;
+                        }
+                        layout.variants.push((name_of(dwarf, unit, entry)?, value));
+                        variants.extend(type_ref(entry)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Declaration order doesn't matter, only where fields end up.
+    layout
+        .fields
+        .sort_by(|a, b| (a.offset, &a.name).cmp(&(b.offset, &b.name)));
+
+    let named = matches!(
+        tag,
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type | gimli::DW_TAG_enumeration_type
+    );
+    let scope = match name {
+        Some(name) if named => {
+            collected.layouts.entry(name.clone()).or_insert(layout);
+            name
+        }
+        _ => String::new(),
+    };
+
+    for offset in refs {
+        collect(dwarf, unit, paths, offset, "", depth + 1, collected)?;
+    }
+    for offset in variants {
+        collect(dwarf, unit, paths, offset, &scope, depth + 1, collected)?;
+    }
+    Ok(())
+}
+
+fn field(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    paths: &HashMap<UnitOffset, String>,
+    entry: &gimli::DebuggingInformationEntry<Slice>,
+) -> gimli::Result<Field> {
+    Ok(Field {
+        name: name_of(dwarf, unit, entry)?,
+        offset: number(entry.attr_value(gimli::DW_AT_data_member_location)?),
+        ty: match type_ref(entry)? {
+            Some(ty) => type_name(dwarf, unit, paths, ty, 0)?,
+            None => "?".into(),
+        },
+    })
+}
+
+fn type_ref(entry: &gimli::DebuggingInformationEntry<Slice>) -> gimli::Result<Option<UnitOffset>> {
+    Ok(match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => Some(offset),
+        _ => None,
+    })
+}
+
+fn name_of(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    entry: &gimli::DebuggingInformationEntry<Slice>,
+) -> gimli::Result<String> {
+    Ok(match entry.attr_value(gimli::DW_AT_name)? {
+        Some(name) => dwarf
+            .attr_string(unit, name)?
+            .to_string_lossy()
+            .into_owned(),
+        None => "?".into(),
+    })
+}
+
+fn number(value: Option<AttributeValue<Slice>>) -> Option<u64> {
+    let value = value?;
+    value
+        .udata_value()
+        .or_else(|| value.sdata_value().map(|value| value as u64))
+}
+
+/// `name` of the type at `offset` with its namespace, if it has one.
+fn qualified(paths: &HashMap<UnitOffset, String>, offset: UnitOffset, name: String) -> String {
+    match paths.get(&offset) {
+        Some(path) => format!("{}::{}", path, name),
+        None => name,
+    }
+}
+
+fn type_name(
+    dwarf: &gimli::Dwarf<Slice>,
+    unit: &Unit,
+    paths: &HashMap<UnitOffset, String>,
+    offset: UnitOffset,
+    depth: usize,
+) -> gimli::Result<String> {
+    let entry = unit.entry(offset)?;
+    if entry.attr_value(gimli::DW_AT_name)?.is_some() {
+        return Ok(qualified(paths, offset, name_of(dwarf, unit, &entry)?));
+    }
+
+    // Unnamed pointers and qualifiers, name them after what they point to.
+    let inner = match type_ref(&entry)? {
+        Some(offset) if depth < 8 => Some(type_name(dwarf, unit, paths, offset, depth + 1)?),
+        _ => None,
+    };
+    Ok(match (entry.tag(), inner) {
+        (gimli::DW_TAG_pointer_type, Some(inner)) => format!("*{}", inner),
+        (_, Some(inner)) => inner,
+        (_, None) => "?".into(),
+    })
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Versioned copies of the patch library */
+
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use cargo_metadata::MetadataCommand;
+
+/// Every reload hands the injector a fresh copy of the library, in
+/// `target/plonk/<plonk pid>/<library>-<generation>.<ext>`. `dlopen` returns
+/// the image it already loaded for a path it has seen, and cargo overwriting
+/// a library the program has mapped can crash it.
+#[derive(Default)]
+pub struct Generations {
+    dir: PathBuf,
+    next: u32,
+    /// Copies the running program may have loaded, oldest first.
+    live: Vec<PathBuf>,
+}
+
+impl Generations {
+    /// Create the directory of this session, which also tells `sessions` that
+    /// it is running.
+    pub fn open(&mut self) -> io::Result<&Path> {
+        if self.dir.as_os_str().is_empty() {
+            let root = root()?;
+            collect_stale(&root);
+            self.dir = root.join(std::process::id().to_string());
+            std::fs::create_dir_all(&self.dir)?;
+        }
+        Ok(&self.dir)
+    }
+
+    /// Copy `library` to the next generation.
+    pub fn copy(&mut self, library: &Path) -> io::Result<PathBuf> {
+        self.open()?;
+
+        let stem = library.file_stem().unwrap_or_default().to_string_lossy();
+        let mut name = format!("{}-{}", stem, self.next);
+        if let Some(ext) = library.extension() {
+            name = format!("{}.{}", name, ext.to_string_lossy());
+        }
+        let path = self.dir.join(name);
+        std::fs::copy(library, &path)?;
+
+        self.next += 1;
+        self.live.push(path.clone());
+        Ok(path)
+    }
+
+    /// Delete a copy the injector unloaded.
+    pub fn remove(&mut self, path: &str) {
+        self.live.retain(|live| live.as_os_str() != path);
+        let _ = std::fs::remove_file(path);
+    }
+
+    /// Delete all but the newest `keep` copies, once no running program can
+    /// have the older ones loaded.
+    pub fn collect(&mut self, keep: usize) {
+        let old = self.live.len().saturating_sub(keep);
+        for path in self.live.drain(..old) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn root() -> io::Result<PathBuf> {
+    let meta = MetadataCommand::new()
+        .no_deps()
+        .exec()
+        .map_err(io::Error::other)?;
+    Ok(meta.target_directory.into_std_path_buf().join("plonk"))
+}
+
+/// Process ids of the other plonk sessions running in this workspace.
+pub fn sessions() -> io::Result<Vec<u32>> {
+    let mut pids = Vec::new();
+    let entries = match std::fs::read_dir(root()?) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(pids),
+        Err(err) => return Err(err),
+    };
+
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_str().and_then(|name| name.parse().ok());
+        if let Some(pid) = pid {
+            if pid != std::process::id() && alive(pid) {
+                pids.push(pid);
+            }
+        }
+    }
+    Ok(pids)
+}
+
+/// Remove the copies of plonk sessions that are gone.
+fn collect_stale(root: &Path) {
+    let entries = match std::fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_str().and_then(|name| name.parse().ok());
+        if matches!(pid, Some(pid) if !alive(pid)) {
+            let _ = std::fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn alive(pid: u32) -> bool {
+    let found = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    found || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn alive(_pid: u32) -> bool {
+    // Loaded libraries can't be deleted anyway.
+    true
+}
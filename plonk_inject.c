@@ -22,14 +22,46 @@
 
 /* Injector dynamic library for Plonk */
 
+#define _GNU_SOURCE
+#include <stdarg.h>
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
+
+/* Must match `PLONK_PROTOCOL` in plonk.rs */
+#define PLONK_PROTOCOL_VERSION 11
 
 #if defined(__APPLE__) || defined(__linux__)
+#define PLONK_UNIX
 #include <dlfcn.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <pthread.h>
+#include <sys/socket.h>
+#include <sys/un.h>
+#include <time.h>
+#include <unistd.h>
 #define plonk_dlopen(name) dlopen(name, RTLD_LAZY)
+#define plonk_dlclose(handle) dlclose(handle)
 #define plonk_dlerror() dlerror()
 #define plonk_getenv(name) getenv(name)
+#define plonk_unsetenv(name) unsetenv(name)
+#endif
+
+#if defined(__linux__)
+#include <dirent.h>
+#include <link.h>
+#include <sched.h>
+#include <semaphore.h>
+#include <signal.h>
+#include <sys/syscall.h>
+#include <ucontext.h>
+#endif
+
+#if defined(__APPLE__)
+#define PLONK_PRELOAD_ENV "DYLD_INSERT_LIBRARIES"
+#elif defined(__linux__)
+#define PLONK_PRELOAD_ENV "LD_PRELOAD"
 #endif
 
 #if defined(_WIN32)
@@ -55,72 +87,1546 @@ char *plonk_getenv(const char *name)
   return buf;
 }
 #define plonk_dlopen(name) LoadLibrary(name)
+#define plonk_dlclose(handle) FreeLibrary(handle)
 #define plonk_dlerror() dlerror()
+#define plonk_unsetenv(name) SetEnvironmentVariable(name, NULL)
 
 #endif
 
 #include "frida-gum.h"
-  
-__attribute__((constructor))
-static void init() 
+
+/* Same levels as `LogLevel` in plonk.rs */
+enum plonk_log_level {
+  PLONK_LOG_ERROR,
+  PLONK_LOG_WARN,
+  PLONK_LOG_INFO,
+  PLONK_LOG_DEBUG,
+};
+
+static int log_level = PLONK_LOG_WARN;
+static FILE *log_file;
+
+/* Diagnostics go to stderr or PLONK_LOG_FILE, never the program's stdout */
+static void plonk_log(int level, const char *fmt, ...)
 {
-  GumInterceptor * interceptor;
-  char *sym, *new_sym, *lib, *bin, *verbose;
-  void *dl, *original, *new;
+  va_list args;
+  FILE *out;
 
-  sym = plonk_getenv("SYMBOL");
-  new_sym = plonk_getenv("NEW_SYMBOL");
+  if (level > log_level)
+    return;
 
-  /* Library with the new symbols */
-  lib = plonk_getenv("PLONK_LIBRARY");
-  /* Binary with the original symbols */
-  bin = plonk_getenv("PLONK_BINARY");
-  verbose = plonk_getenv("VERBOSE");
+  out = log_file ? log_file : stderr;
+  va_start(args, fmt);
+  fputs("[*] ", out);
+  vfprintf(out, fmt, args);
+  fputc('\n', out);
+  fflush(out);
+  va_end(args);
+}
 
-  if (!sym || !lib)
+static void log_init()
+{
+  char *level, *path;
+
+  level = plonk_getenv("PLONK_LOG");
+  path = plonk_getenv("PLONK_LOG_FILE");
+
+  if (level) {
+    if (!strcmp(level, "error"))
+      log_level = PLONK_LOG_ERROR;
+    else if (!strcmp(level, "warn"))
+      log_level = PLONK_LOG_WARN;
+    else if (!strcmp(level, "info"))
+      log_level = PLONK_LOG_INFO;
+    else if (!strcmp(level, "debug"))
+      log_level = PLONK_LOG_DEBUG;
+  }
+
+  if (path) {
+    log_file = fopen(path, "a");
+    if (!log_file)
+      plonk_log(PLONK_LOG_WARN, "Could not open log file %s", path);
+  }
+}
+
+/* Variables of the plonk protocol, removed by `scrub()` */
+static const char *plonk_vars[] = {
+  "PLONK_PROTOCOL",
+  "PLONK_SYMBOL",
+  "PLONK_NEW_SYMBOL",
+  "PLONK_LIBRARY",
+  "PLONK_BINARY",
+  "PLONK_LOG",
+  "PLONK_LOG_FILE",
+  "PLONK_SCRUB",
+  "PLONK_CONTROL",
+  "PLONK_TIMINGS",
+  "PLONK_STOP_WORLD",
+  "PLONK_UNLOAD",
+  "PLONK_BIND",
+  "PLONK_CALLS",
+  NULL,
+};
+
+#ifdef PLONK_PRELOAD_ENV
+/* Remove this library from the preload list, keeping any other entries */
+static void scrub_preload()
+{
+  Dl_info info;
+  char *preload, *copy, *entry, *rest, *out;
+  size_t len;
+
+  preload = getenv(PLONK_PRELOAD_ENV);
+  if (!preload || !dladdr((void *) scrub_preload, &info) || !info.dli_fname)
     return;
+
+  copy = strdup(preload);
+  out = calloc(1, strlen(preload) + 1);
+  rest = copy;
+  while ((entry = strsep(&rest, ": ")) != NULL) {
+    if (!*entry || !strcmp(entry, info.dli_fname))
+      continue;
+    len = strlen(out);
+    if (len)
+      out[len++] = ':';
+    strcpy(out + len, entry);
+  }
+
+  if (*out)
+    setenv(PLONK_PRELOAD_ENV, out, 1);
+  else
+    unsetenv(PLONK_PRELOAD_ENV);
+
+  free(copy);
+  free(out);
+}
+#endif
+
+/* Hide plonk from processes spawned by the program */
+static void scrub()
+{
+  const char **var;
+
+#ifdef PLONK_PRELOAD_ENV
+  scrub_preload();
+#endif
+  for (var = plonk_vars; *var; var++)
+    plonk_unsetenv(*var);
+}
+
+static GumInterceptor *interceptor;
+/* Binary with the original symbols */
+static char *bin_path;
+
+/* Allocator shim functions, see `generation_bind()` */
+#define PLONK_ALLOCATOR 4
+
+/* A patch library, plonk hands over a new copy for every reload */
+struct generation {
+  /* Counts up from 1, 0 stands for the binary's own code */
+  unsigned id;
+  char *path;
+  void *handle;
+  GumMemoryRange range;
+  /* Functions of the library sent to the binary's allocator */
+  gpointer redirects[PLONK_ALLOCATOR];
+  int redirect_count;
+  struct generation *next;
+};
+
+/* Earlier replacements kept per function for `revert` */
+#define PLONK_HISTORY 8
+
+struct replacement {
+  struct generation *gen;
+  gpointer address;
+};
+
+/* A replaced function of the binary */
+struct patch {
+  char *sym;
+  gpointer original;
+  /* Oldest first */
+  struct replacement history[PLONK_HISTORY];
+  int len;
+  /* Index of the installed replacement, -1 when reverted to the original */
+  int current;
+  /* For functions too small to hook, where their callers go instead, see `calls_queue()` */
+  gpointer trampoline;
+  struct patch *next;
+};
+
+/* Newest first, the head is the active generation */
+static struct generation *generations;
+static unsigned generation_count;
+static struct patch *patches;
+
+/* Microseconds spent loading libraries and patching since the last report */
+static long long load_us, patch_us;
+
+/* Monotonic clock in microseconds */
+static long long now_us()
+{
+#ifdef PLONK_UNIX
+  struct timespec ts;
+
+  clock_gettime(CLOCK_MONOTONIC, &ts);
+  return ts.tv_sec * 1000000LL + ts.tv_nsec / 1000;
+#else
+  LARGE_INTEGER freq, count;
+
+  QueryPerformanceFrequency(&freq);
+  QueryPerformanceCounter(&count);
+  return count.QuadPart * 1000000LL / freq.QuadPart;
+#endif
+}
+
+static const char *plonk_error(const char *fmt, ...)
+{
+  static char error[512];
+  va_list args;
+
+  va_start(args, fmt);
+  vsnprintf(error, sizeof(error), fmt, args);
+  va_end(args);
+
+  plonk_log(PLONK_LOG_ERROR, "%s", error);
+  return error;
+}
+
+static gboolean find_range(const GumModuleDetails *details, gpointer user_data)
+{
+  struct generation *gen = user_data;
+
+  if (!details->path || strcmp(details->path, gen->path))
+    return TRUE;
+  gen->range = *details->range;
+  return FALSE;
+}
+
+/* Load `lib`, or find it if it was loaded already */
+static struct generation *generation_open(const char *lib)
+{
+  struct generation *gen;
+  void *handle;
+
+  for (gen = generations; gen; gen = gen->next) {
+    if (!strcmp(gen->path, lib))
+      return gen;
+  }
+
+  handle = plonk_dlopen(lib);
+  if (!handle)
+    return NULL;
+
+  gen = calloc(1, sizeof(*gen));
+  gen->id = ++generation_count;
+  gen->path = strdup(lib);
+  gen->handle = handle;
+  gum_process_enumerate_modules(find_range, gen);
+  gen->next = generations;
+  generations = gen;
+  return gen;
+}
+
+static struct patch *patch_find(gpointer original)
+{
+  struct patch *patch;
+
+  for (patch = patches; patch; patch = patch->next) {
+    if (patch->original == original)
+      return patch;
+  }
+  return NULL;
+}
+
+/* The patch of `sym` at `original`, made on first use */
+static struct patch *patch_get(const char *sym, gpointer original)
+{
+  struct patch *patch;
+
+  patch = patch_find(original);
+  if (!patch) {
+    patch = calloc(1, sizeof(*patch));
+    patch->sym = strdup(sym);
+    patch->original = original;
+    patch->current = -1;
+    patch->next = patches;
+    patches = patch;
+  }
+  return patch;
+}
+
+/* Remember that `sym` at `original` now runs `address` from `gen` */
+static void patch_set(const char *sym, gpointer original, struct generation *gen,
+                      gpointer address)
+{
+  struct patch *patch;
+
+  patch = patch_get(sym, original);
+
+  /* Forget the oldest, it's installed only when reverted to */
+  if (patch->len == PLONK_HISTORY) {
+    memmove(patch->history, patch->history + 1,
+            (PLONK_HISTORY - 1) * sizeof(struct replacement));
+    patch->len--;
+  }
+  patch->history[patch->len].gen = gen;
+  patch->history[patch->len].address = address;
+  patch->current = patch->len++;
+}
+
+/* Whether some function runs, or may be rolled back to, code from `gen` */
+static gboolean generation_referenced(struct generation *gen)
+{
+  struct patch *patch;
+  int i;
+
+  for (patch = patches; patch; patch = patch->next) {
+    for (i = 0; i < patch->len; i++) {
+      if (patch->history[i].gen == gen)
+        return TRUE;
+    }
+  }
+  return FALSE;
+}
+
+/* Find `sym` in the binary */
+static gpointer find_original(const char *sym)
+{
+  gpointer original;
+
+  original = GSIZE_TO_POINTER(gum_module_find_export_by_name(NULL, sym));
+  if (!original && bin_path)
+    original = GSIZE_TO_POINTER(gum_module_find_symbol_by_name(bin_path, sym));
+  return original;
+}
+
+/* Split off the next `delim` separated field of `*rest`, like strsep(3) */
+static char *next_field(char **rest, char delim)
+{
+  char *field = *rest, *end;
+
+  if (!field)
+    return NULL;
+  end = strchr(field, delim);
+  if (end) {
+    *end = '\0';
+    *rest = end + 1;
+  } else {
+    *rest = NULL;
+  }
+  return field;
+}
+
+/* Write `value` to `slot`, which is usually in a page made read-only after relocation */
+static gboolean slot_write(gpointer slot, guint64 value)
+{
+  GumPageProtection prot;
+  gboolean readonly;
+
+  if (!gum_memory_query_protection(slot, &prot))
+    return FALSE;
+  readonly = !(prot & GUM_PAGE_WRITE);
+  if (readonly && !gum_try_mprotect(slot, sizeof(value), prot | GUM_PAGE_WRITE))
+    return FALSE;
+  memcpy(slot, &value, sizeof(value));
+  if (readonly)
+    gum_mprotect(slot, sizeof(value), prot);
+  return TRUE;
+}
+
+struct code_write {
+  gconstpointer bytes;
+  gsize size;
+};
+
+static void code_copy(gpointer mem, gpointer user_data)
+{
+  struct code_write *write = user_data;
+
+  memcpy(mem, write->bytes, write->size);
+}
+
+/* Overwrite `size` bytes of code at `address` */
+static gboolean code_write(gpointer address, gconstpointer bytes, gsize size)
+{
+  struct code_write write = { bytes, size };
+
+  return gum_memory_patch_code(address, size, code_copy, &write);
+}
+
+#if defined(__x86_64__)
+/* jmp [rip + 2], followed by the address at offset 8 */
+static const guint8 trampoline_jump[8] = { 0xff, 0x25, 0x02, 0x00, 0x00, 0x00, 0xcc, 0xcc };
+/* How far a rel32 call reaches */
+#define PLONK_CALL_RANGE ((gint64) G_MAXINT32)
+#elif defined(__aarch64__)
+/* ldr x16, #8; br x16, followed by the address at offset 8 */
+static const guint8 trampoline_jump[8] = { 0x50, 0x00, 0x00, 0x58, 0x00, 0x02, 0x1f, 0xd6 };
+/* How far b and bl reach */
+#define PLONK_CALL_RANGE ((gint64) 1 << 27)
+#endif
+
+enum site_kind {
+  SITE_ABS64,
+  SITE_REL32,
+  SITE_BL26,
+};
+
+/* A place reaching a function too small to hook */
+struct site {
+  enum site_kind kind;
+  gpointer address;
+  /* What to write for the trampoline, and the bytes it replaces */
+  guint8 bytes[8], saved[8];
+  gsize size;
+};
+
+/* Where the callers of a function too small to hook go instead, see `calls_queue()` */
+struct redirect {
+  gpointer trampoline;
+  struct site *sites;
+  int len;
+};
+
+static void redirect_free(struct redirect *redirect)
+{
+  if (redirect->trampoline)
+    gum_memory_free(redirect->trampoline, gum_query_page_size());
+  free(redirect->sites);
+  memset(redirect, 0, sizeof(*redirect));
+}
+
+#ifdef PLONK_CALL_RANGE
+#define PLONK_TRAMPOLINE 16
+
+/* A trampoline within reach of calls around `original`, jumping to it */
+static gpointer trampoline_new(gpointer original)
+{
+  GumAddressSpec spec = { original, PLONK_CALL_RANGE / 2 };
+  guint8 code[PLONK_TRAMPOLINE];
+  guint64 target = GUM_ADDRESS(original);
+  gpointer trampoline;
+
+  trampoline = gum_memory_allocate_near(&spec, gum_query_page_size(), gum_query_page_size(),
+                                        GUM_PAGE_RX);
+  if (!trampoline)
+    return NULL;
+  memcpy(code, trampoline_jump, sizeof(trampoline_jump));
+  memcpy(code + sizeof(trampoline_jump), &target, sizeof(target));
+  if (!code_write(trampoline, code, sizeof(code))) {
+    gum_memory_free(trampoline, gum_query_page_size());
+    return NULL;
+  }
+  return trampoline;
+}
+
+static void trampoline_store(gpointer mem, gpointer user_data)
+{
+  __atomic_store_n((guint64 *) mem, *(guint64 *) user_data, __ATOMIC_RELEASE);
+}
+
+/* Send the calls going through `trampoline` to `address`, in one store */
+static gboolean trampoline_set(gpointer trampoline, gpointer address)
+{
+  guint64 target = GUM_ADDRESS(address);
+
+  return gum_memory_patch_code((guint8 *) trampoline + sizeof(trampoline_jump),
+                               sizeof(target), trampoline_store, &target);
+}
+
+/*
+ * Prepare pointing the places reaching `sym` at `original` at a new
+ * trampoline, for functions too small to hook. Patching then only changes
+ * where the trampoline jumps. Nothing is written until `apply()`. `calls`
+ * comes from plonk_calls.rs, comma separated entries of:
+ *
+ *   rel32:<symbol>:<offset>   displacement of an x86_64 call or jump
+ *   bl26:<symbol>:<offset>    aarch64 b or bl
+ *   abs64:<symbol>:<offset>   pointer to the function
+ *
+ * with offsets from the symbol. `redirect` stays empty without entries for `sym`.
+ */
+static const char *calls_queue(const char *sym, gpointer original, const char *calls,
+                               struct redirect *redirect)
+{
+  char *list, *rest, *entry, *kind, *name;
+  const char *err = NULL;
+  struct site *site;
+  gint64 distance, offset;
+  guint64 target;
+  guint32 insn;
+  gint32 disp;
+
+  if (!calls || !*calls)
+    return NULL;
+
+  list = strdup(calls);
+  rest = list;
+  while (!err && (entry = next_field(&rest, ',')) != NULL) {
+    kind = next_field(&entry, ':');
+    name = next_field(&entry, ':');
+    if (!name || !entry) {
+      err = plonk_error("Malformed call site %s", kind);
+      break;
+    }
+    if (strcmp(name, sym))
+      continue;
+
+    if (!redirect->trampoline && !(redirect->trampoline = trampoline_new(original))) {
+      err = plonk_error("Could not allocate a trampoline near %s", sym);
+      break;
+    }
+    redirect->sites = realloc(redirect->sites, (redirect->len + 1) * sizeof(struct site));
+    site = &redirect->sites[redirect->len++];
+    offset = strtoll(entry, NULL, 10);
+    site->address = (guint8 *) original + offset;
+    distance = (gint64) (GUM_ADDRESS(redirect->trampoline) - GUM_ADDRESS(site->address));
+
+    if (!strcmp(kind, "abs64")) {
+      site->kind = SITE_ABS64;
+      site->size = sizeof(target);
+      target = GUM_ADDRESS(redirect->trampoline);
+      memcpy(site->bytes, &target, sizeof(target));
+    } else if (!strcmp(kind, "rel32")) {
+      site->kind = SITE_REL32;
+      site->size = sizeof(disp);
+      /* Relative to the end of the instruction, which the displacement ends */
+      distance -= sizeof(disp);
+      disp = (gint32) distance;
+      memcpy(site->bytes, &disp, sizeof(disp));
+      if (disp != distance)
+        err = plonk_error("The call at %s%+lld can't reach a trampoline", sym, offset);
+    } else if (!strcmp(kind, "bl26")) {
+      site->kind = SITE_BL26;
+      site->size = sizeof(insn);
+      memcpy(&insn, site->address, sizeof(insn));
+      insn = (insn & 0xfc000000) | ((guint32) (distance >> 2) & 0x03ffffff);
+      memcpy(site->bytes, &insn, sizeof(insn));
+      if (distance < -PLONK_CALL_RANGE || distance >= PLONK_CALL_RANGE)
+        err = plonk_error("The call at %s%+lld can't reach a trampoline", sym, offset);
+    } else {
+      err = plonk_error("Malformed call site %s", kind);
+      break;
+    }
+    memcpy(site->saved, site->address, site->size);
+  }
+  free(list);
+
+  if (err)
+    redirect_free(redirect);
+  return err;
+}
+
+/* Write `bytes` to `site` */
+static gboolean site_write(struct site *site, const guint8 *bytes)
+{
+  guint64 value;
+
+  if (site->kind != SITE_ABS64)
+    return code_write(site->address, bytes, site->size);
+  memcpy(&value, bytes, sizeof(value));
+  return slot_write(site->address, value);
+}
+
+/* Send every site of `redirect` to its trampoline, or put them all back */
+static gboolean sites_write(struct redirect *redirect)
+{
+  int i;
+
+  for (i = 0; i < redirect->len; i++) {
+    if (!site_write(&redirect->sites[i], redirect->sites[i].bytes))
+      break;
+  }
+  if (i == redirect->len)
+    return TRUE;
+
+  /* The next reload tries again with a new trampoline */
+  while (i-- > 0)
+    site_write(&redirect->sites[i], redirect->sites[i].saved);
+  return FALSE;
+}
+#else
+static gboolean trampoline_set(gpointer trampoline, gpointer address)
+{
+  return FALSE;
+}
+
+static const char *calls_queue(const char *sym, gpointer original, const char *calls,
+                               struct redirect *redirect)
+{
+  return NULL;
+}
+
+static gboolean sites_write(struct redirect *redirect)
+{
+  return FALSE;
+}
+#endif
+
+/* A change to one function, installed along with the rest of a batch */
+struct pending {
+  const char *sym;
+  gpointer original;
+  /* NULL restores the original code */
+  gpointer address;
+  struct generation *gen;
+  /* For reverts, the patch and the history entry to install (-1 for none) */
+  struct patch *patch;
+  int index;
+  /* Call sites to send to a new trampoline, for functions too small to hook */
+  struct redirect redirect;
+  GumReplaceReturn ret;
+};
+
+struct batch {
+  struct pending *items;
+  int len, cap;
+};
+
+static struct pending *batch_push(struct batch *batch)
+{
+  if (batch->len == batch->cap) {
+    batch->cap = batch->cap ? batch->cap * 2 : 8;
+    batch->items = realloc(batch->items, batch->cap * sizeof(struct pending));
+  }
+  memset(&batch->items[batch->len], 0, sizeof(struct pending));
+  return &batch->items[batch->len++];
+}
+
+/* Free the batch and the trampolines it didn't install */
+static void batch_free(struct batch *batch)
+{
+  int i;
+
+  for (i = 0; i < batch->len; i++)
+    redirect_free(&batch->items[i].redirect);
+  free(batch->items);
+}
+
+/*
+ * Queue replacing `sym` in the binary with `new_sym` from `gen`, through the call
+ * sites in `calls` if it has some, NULL on success
+ */
+static const char *plonk(struct batch *batch, const char *sym, const char *new_sym,
+                         struct generation *gen, const char *calls)
+{
+  struct redirect redirect = { 0 };
+  struct pending *pending;
+  struct patch *patch;
+  void *original, *new;
+  const char *err;
+
   /* Assume same identifier as the original symbol */
   if (!new_sym)
     new_sym = sym;
 
-  gum_init_embedded();
+  original = find_original(sym);
+  if (!original)
+    return plonk_error("Could not find symbol %s in bin", sym);
 
-  interceptor = gum_interceptor_obtain();
+  new = GSIZE_TO_POINTER (gum_module_find_export_by_name(gen->path, new_sym));
+  if (!new)
+    return plonk_error("Could not find symbol %s in %s", new_sym, gen->path);
 
-  original = GSIZE_TO_POINTER(gum_module_find_export_by_name(NULL, sym));
-  if (!original)
-    original = GSIZE_TO_POINTER(gum_module_find_symbol_by_name(bin, sym));
-  if (!original) {
-    fprintf(stderr, "[*] Could not find symbol %s in bin\n", sym);
+  if (new == original)
+    return plonk_error("New symbol %s is the same as the original", new_sym);
+
+  /* Calls keep going to the original until the batch is applied */
+  patch = patch_find(original);
+  if (!patch || !patch->trampoline) {
+    err = calls_queue(sym, original, calls, &redirect);
+    if (err)
+      return err;
+  }
+
+  plonk_log(PLONK_LOG_DEBUG, "Plonking %s in %s", sym, gen->path);
+  plonk_log(PLONK_LOG_DEBUG, "Old address: %p", original);
+  plonk_log(PLONK_LOG_DEBUG, "New address: %p", new);
+
+  pending = batch_push(batch);
+  pending->sym = sym;
+  pending->original = original;
+  pending->address = new;
+  pending->gen = gen;
+  pending->redirect = redirect;
+  return NULL;
+}
+
+/* Queue installing generation `id` of `patch` again, 0 restores the binary's code */
+static const char *patch_revert(struct batch *batch, struct patch *patch, unsigned id)
+{
+  struct pending *pending;
+  int i = -1;
+
+  if (id) {
+    for (i = patch->len - 1; i >= 0; i--) {
+      if (patch->history[i].gen->id == id)
+        break;
+    }
+    if (i < 0)
+      return plonk_error("%s has no generation %u to revert to", patch->sym, id);
+  }
+
+  pending = batch_push(batch);
+  pending->sym = patch->sym;
+  pending->original = patch->original;
+  pending->address = i >= 0 ? patch->history[i].address : NULL;
+  pending->patch = patch;
+  pending->index = i;
+  return NULL;
+}
+
+/* Set by PLONK_STOP_WORLD, park the other threads while patching */
+static int stop_world;
+/* Seconds the other threads stay parked at most */
+#define PLONK_STOP_TIMEOUT 1
+
+#if defined(__linux__) && (defined(__x86_64__) || defined(__aarch64__))
+/* Parks threads in `park()` */
+#define PLONK_STOP_SIGNAL (SIGRTMIN + 5)
+/* Most bytes at the start of a function a replacement overwrites */
+#define PLONK_PROLOGUE 32
+#define PLONK_MAX_THREADS 4096
+
+static int world_sent, world_slots, world_parked, world_left, world_resumed;
+static gpointer world_pcs[PLONK_MAX_THREADS];
+/* Posted when the threads are parked and when they may go again */
+static sem_t world_stopped, world_done;
+static pid_t world_watchdog;
+static int world_watched, world_late;
+
+static void park(int sig, siginfo_t *info, void *context)
+{
+  ucontext_t *uc = context;
+  int saved = errno, slot;
+
+  slot = __atomic_fetch_add(&world_slots, 1, __ATOMIC_RELAXED);
+  if (slot < PLONK_MAX_THREADS) {
+#if defined(__x86_64__)
+    world_pcs[slot] = (gpointer) uc->uc_mcontext.gregs[REG_RIP];
+#else
+    world_pcs[slot] = (gpointer) uc->uc_mcontext.pc;
+#endif
+  }
+  __atomic_fetch_add(&world_parked, 1, __ATOMIC_RELEASE);
+
+  while (!__atomic_load_n(&world_resumed, __ATOMIC_ACQUIRE))
+    sched_yield();
+
+  __atomic_fetch_add(&world_left, 1, __ATOMIC_RELEASE);
+  errno = saved;
+}
+
+/* Wait up to 100ms for `*counter` to reach `n` */
+static gboolean wait_count(int *counter, int n)
+{
+  struct timespec pause = { 0, 100000 };
+  int i;
+
+  for (i = 0; i < 1000; i++) {
+    if (__atomic_load_n(counter, __ATOMIC_ACQUIRE) >= n)
+      return TRUE;
+    nanosleep(&pause, NULL);
+  }
+  return FALSE;
+}
+
+/*
+ * Signal every thread but this one, returns how many got it. Threads started
+ * meanwhile aren't stopped, but they can't be in the middle of old code.
+ */
+static int world_signal()
+{
+  DIR *dir;
+  struct dirent *entry;
+  pid_t self, tid;
+  int sent = 0;
+
+  dir = opendir("/proc/self/task");
+  if (!dir)
+    return -1;
+
+  self = syscall(SYS_gettid);
+  while ((entry = readdir(dir)) != NULL) {
+    tid = atoi(entry->d_name);
+    if (tid <= 0 || tid == self || tid == world_watchdog)
+      continue;
+    if (!syscall(SYS_tgkill, getpid(), tid, PLONK_STOP_SIGNAL))
+      sent++;
+  }
+  closedir(dir);
+  return sent;
+}
+
+static void world_resume()
+{
+  if (!world_sent)
+    return;
+
+  if (world_watched) {
+    sem_post(&world_done);
+    world_watched = 0;
+  }
+  __atomic_store_n(&world_resumed, 1, __ATOMIC_RELEASE);
+  /* The counters are reset by the next stop */
+  wait_count(&world_left, world_sent);
+  world_sent = 0;
+}
+
+/* Whether `pc` is at a call site of `redirect` */
+static gboolean site_busy(struct redirect *redirect, GumAddress pc)
+{
+  GumAddress address;
+  int i;
+
+  for (i = 0; i < redirect->len; i++) {
+    if (redirect->sites[i].kind == SITE_ABS64)
+      continue;
+    /* A rel32 displacement starts at most 2 bytes into its instruction */
+    address = GUM_ADDRESS(redirect->sites[i].address);
+    if (pc + 2 >= address && pc < address + redirect->sites[i].size)
+      return TRUE;
+  }
+  return FALSE;
+}
+
+/* Whether a parked thread is inside code the batch overwrites */
+static gboolean world_busy(struct batch *batch)
+{
+  GumAddress pc, original;
+  int i, j, parked;
+
+  parked = world_parked < PLONK_MAX_THREADS ? world_parked : PLONK_MAX_THREADS;
+  for (i = 0; i < parked; i++) {
+    pc = GUM_ADDRESS(world_pcs[i]);
+    for (j = 0; j < batch->len; j++) {
+      original = GUM_ADDRESS(batch->items[j].original);
+      if (pc >= original && pc < original + PLONK_PROLOGUE)
+        return TRUE;
+      if (site_busy(&batch->items[j].redirect, pc))
+        return TRUE;
+    }
+  }
+  return FALSE;
+}
+
+/* Park the other threads once none of them is inside code the batch overwrites */
+static const char *world_stop(struct batch *batch)
+{
+  struct timespec pause = { 0, 1000000 };
+  int attempt;
+
+  if (!stop_world)
+    return NULL;
+
+  for (attempt = 0; attempt < 100; attempt++) {
+    __atomic_store_n(&world_slots, 0, __ATOMIC_RELAXED);
+    __atomic_store_n(&world_parked, 0, __ATOMIC_RELAXED);
+    __atomic_store_n(&world_left, 0, __ATOMIC_RELAXED);
+    __atomic_store_n(&world_resumed, 0, __ATOMIC_RELEASE);
+
+    world_sent = world_signal();
+    if (world_sent < 0) {
+      world_sent = 0;
+      return plonk_error("Could not list threads: %s", strerror(errno));
+    }
+    if (!wait_count(&world_parked, world_sent)) {
+      world_resume();
+      return plonk_error("Could not stop the other threads, do they block signal %d?",
+                         PLONK_STOP_SIGNAL);
+    }
+    if (!world_busy(batch)) {
+      __atomic_store_n(&world_late, 0, __ATOMIC_RELAXED);
+      world_watched = 1;
+      sem_post(&world_stopped);
+      return NULL;
+    }
+
+    world_resume();
+    nanosleep(&pause, NULL);
+  }
+  return plonk_error("Threads kept running the code to be patched, try again");
+}
+
+/*
+ * Let the parked threads go if the patching thread doesn't within
+ * PLONK_STOP_TIMEOUT, it's likely waiting for a lock one of them holds.
+ */
+static void *watchdog(void *data)
+{
+  struct timespec deadline;
+  sigset_t all;
+
+  sigfillset(&all);
+  pthread_sigmask(SIG_BLOCK, &all, NULL);
+  __atomic_store_n(&world_watchdog, syscall(SYS_gettid), __ATOMIC_RELEASE);
+
+  for (;;) {
+    sem_wait(&world_stopped);
+    clock_gettime(CLOCK_REALTIME, &deadline);
+    deadline.tv_sec += PLONK_STOP_TIMEOUT;
+    if (sem_timedwait(&world_done, &deadline)) {
+      __atomic_store_n(&world_late, 1, __ATOMIC_RELAXED);
+      __atomic_store_n(&world_resumed, 1, __ATOMIC_RELEASE);
+      sem_wait(&world_done);
+    }
+  }
+  return NULL;
+}
+
+static void world_init()
+{
+  struct sigaction action;
+  pthread_t thread;
+
+  sem_init(&world_stopped, 0, 0);
+  sem_init(&world_done, 0, 0);
+  if (pthread_create(&thread, NULL, watchdog, NULL)) {
+    plonk_error("Could not start the stop watchdog");
+    stop_world = 0;
+    return;
+  }
+  pthread_detach(thread);
+  /* Signaled, it would never park */
+  while (!__atomic_load_n(&world_watchdog, __ATOMIC_ACQUIRE))
+    sched_yield();
+
+  memset(&action, 0, sizeof(action));
+  action.sa_sigaction = park;
+  action.sa_flags = SA_SIGINFO | SA_RESTART;
+  sigfillset(&action.sa_mask);
+  if (sigaction(PLONK_STOP_SIGNAL, &action, NULL)) {
+    plonk_error("Could not install the stop handler: %s", strerror(errno));
+    stop_world = 0;
+  }
+}
+
+/* Whether the watchdog let the threads go before `world_resume()` */
+static gboolean world_expired()
+{
+  return __atomic_load_n(&world_late, __ATOMIC_RELAXED);
+}
+#else
+static const char *world_stop(struct batch *batch)
+{
+  return NULL;
+}
+
+static void world_resume()
+{
+}
+
+static gboolean world_expired()
+{
+  return FALSE;
+}
+
+static void world_init()
+{
+  plonk_log(PLONK_LOG_WARN, "PLONK_STOP_WORLD is only supported on Linux x86_64 and aarch64");
+  stop_world = 0;
+}
+#endif
+
+/* Whether `pending` goes through the interceptor rather than a trampoline */
+static gboolean pending_hooks(struct pending *pending)
+{
+  struct patch *patch;
+
+  if (pending->redirect.trampoline)
+    return FALSE;
+  patch = pending->patch ? pending->patch : patch_find(pending->original);
+  return !patch || !patch->trampoline;
+}
+
+/* Put back the replacements `apply()` queued in the open transaction */
+static void apply_undo(struct batch *batch)
+{
+  struct pending *pending;
+  struct patch *patch;
+  int i;
+
+  for (i = 0; i < batch->len; i++) {
+    pending = &batch->items[i];
+    if (!pending_hooks(pending))
+      continue;
+    gum_interceptor_revert(interceptor, pending->original);
+    patch = patch_find(pending->original);
+    if (patch && patch->current >= 0)
+      gum_interceptor_replace_fast(interceptor, pending->original,
+                                   patch->history[patch->current].address, NULL);
+  }
+}
+
+/*
+ * Install the whole batch in one transaction, NULL on success. The
+ * interceptor allocates when replacing, so that's done before the other
+ * threads are parked, one of them could hold the malloc lock. Only the writes
+ * happen while they are, when the transaction ends. Ending it still frees
+ * what the reverted hooks used, the watchdog lets the threads go if that
+ * blocks.
+ */
+static const char *apply(struct batch *batch)
+{
+  struct pending *pending;
+  struct patch *patch;
+  const char *err;
+  long long start;
+  int i;
+
+  start = now_us();
+  gum_interceptor_begin_transaction(interceptor);
+  for (i = 0; i < batch->len; i++) {
+    pending = &batch->items[i];
+    if (!pending_hooks(pending))
+      continue;
+    /* Drop the previous replacement, if any */
+    gum_interceptor_revert(interceptor, pending->original);
+    /* Checked below */
+    if (pending->address)
+      pending->ret = gum_interceptor_replace_fast(interceptor, pending->original,
+                                                  pending->address, NULL);
+  }
+
+  err = world_stop(batch);
+  if (err) {
+    apply_undo(batch);
+    gum_interceptor_end_transaction(interceptor);
+    return err;
+  }
+
+  for (i = 0; i < batch->len; i++) {
+    pending = &batch->items[i];
+    /* The trampoline jumps to the new code before the first call reaches it */
+    if (pending->redirect.trampoline) {
+      pending->ret = trampoline_set(pending->redirect.trampoline, pending->address) &&
+                             sites_write(&pending->redirect)
+                         ? GUM_REPLACE_OK
+                         : GUM_REPLACE_POLICY_VIOLATION;
+      continue;
+    }
+    patch = pending->patch ? pending->patch : patch_find(pending->original);
+    if (patch && patch->trampoline) {
+      pending->ret = trampoline_set(patch->trampoline, pending->address ? pending->address
+                                                                        : pending->original)
+                         ? GUM_REPLACE_OK
+                         : GUM_REPLACE_POLICY_VIOLATION;
+    }
+  }
+  gum_interceptor_end_transaction(interceptor);
+
+  world_resume();
+  patch_us += now_us() - start;
+  if (world_expired())
+    plonk_log(PLONK_LOG_WARN,
+              "Patching took over %ds, let the other threads go before it was done",
+              PLONK_STOP_TIMEOUT);
+
+  err = NULL;
+  for (i = 0; i < batch->len; i++) {
+    pending = &batch->items[i];
+    if (pending->ret != GUM_REPLACE_OK && pending->redirect.trampoline) {
+      err = plonk_error("Could not send the calls to %s to a trampoline", pending->sym);
+    } else if (pending->ret != GUM_REPLACE_OK) {
+      err = plonk_error("Could not replace %s (error %d)", pending->sym, pending->ret);
+    } else if (pending->patch) {
+      plonk_log(PLONK_LOG_DEBUG, "Reverted %s", pending->sym);
+      pending->patch->current = pending->index;
+    } else {
+      patch_set(pending->sym, pending->original, pending->gen, pending->address);
+      if (pending->redirect.trampoline) {
+        plonk_log(PLONK_LOG_DEBUG, "%s is too small to hook, patched %d callers",
+                  pending->sym, pending->redirect.len);
+        /* Owned by the patch from now on */
+        patch_find(pending->original)->trampoline = pending->redirect.trampoline;
+        pending->redirect.trampoline = NULL;
+      }
+    }
+  }
+  return err;
+}
+
+#if defined(__linux__)
+/* Find `sym` in the binary only, libstd exports the same symbols as its static std */
+static gpointer find_binary(const char *sym)
+{
+  if (!bin_path)
+    return NULL;
+  return GSIZE_TO_POINTER(gum_module_find_symbol_by_name(bin_path, sym));
+}
+
+/* Send the allocator shim function `sym` that `gen` uses to the binary's */
+static const char *allocator_redirect(struct generation *gen, const char *sym)
+{
+  gpointer ours, theirs;
+  GumReplaceReturn ret;
+
+  ours = sym ? dlsym(gen->handle, sym) : NULL;
+  theirs = sym ? find_binary(sym) : NULL;
+  if (!ours || !theirs || ours == theirs)
+    return NULL;
+
+  ret = gum_interceptor_replace_fast(interceptor, ours, theirs, NULL);
+  /* By an earlier generation, in the libstd they share */
+  if (ret == GUM_REPLACE_ALREADY_REPLACED)
+    return NULL;
+  if (ret != GUM_REPLACE_OK)
+    return plonk_error("Could not send %s to the binary's allocator (error %d)", sym, ret);
+
+  /* Reverted when unloading, the shim of older toolchains is in the library itself */
+  if (GUM_ADDRESS(ours) - gen->range.base_address < gen->range.size &&
+      gen->redirect_count < PLONK_ALLOCATOR)
+    gen->redirects[gen->redirect_count++] = ours;
+  return NULL;
+}
+
+/*
+ * Point the GOT entries of `gen` for statics and dependency functions at the
+ * binary's copies, so patched code keeps the program's state. `bindings` comes
+ * from plonk_bind.rs, comma separated entries of:
+ *
+ *   sym:<offset>:<addend>:<binary symbol>
+ *   tlsmod:<offset>
+ *   tlsoff:<offset>:<offset in the binary's TLS block>
+ *   alloc:<allocator shim symbol>
+ */
+static const char *generation_bind(struct generation *gen, const char *bindings)
+{
+  char *list, *rest, *entry, *kind, *offset, *addend;
+  struct link_map *map;
+  const char *err = NULL;
+  unsigned count = 0;
+  size_t modid = 1;
+  gpointer address;
+  guint64 value;
+#ifdef RTLD_DI_TLS_MODID
+  void *self;
+#endif
+
+  if (!bindings || !*bindings)
+    return NULL;
+  if (dlinfo(gen->handle, RTLD_DI_LINKMAP, &map))
+    return plonk_error("Could not find where %s is loaded: %s", gen->path, dlerror());
+#ifdef RTLD_DI_TLS_MODID
+  /* The binary's thread-locals, always 1 with glibc */
+  self = dlopen(NULL, RTLD_LAZY);
+  if (self) {
+    dlinfo(self, RTLD_DI_TLS_MODID, &modid);
+    dlclose(self);
+  }
+#endif
+
+  list = strdup(bindings);
+  rest = list;
+  while (!err && (entry = next_field(&rest, ',')) != NULL) {
+    kind = next_field(&entry, ':');
+    if (!strcmp(kind, "alloc")) {
+      err = allocator_redirect(gen, entry);
+      continue;
+    }
+
+    offset = next_field(&entry, ':');
+    if (!offset) {
+      err = plonk_error("Malformed binding %s", kind);
+      break;
+    }
+
+    if (!strcmp(kind, "sym")) {
+      addend = next_field(&entry, ':');
+      address = addend && entry ? find_binary(entry) : NULL;
+      /* Stripped from the binary, the library keeps its own */
+      if (!address)
+        continue;
+      value = GUM_ADDRESS(address) + strtoll(addend, NULL, 10);
+    } else if (!strcmp(kind, "tlsmod")) {
+      value = modid;
+    } else if (!strcmp(kind, "tlsoff") && entry) {
+      value = strtoull(entry, NULL, 10);
+    } else {
+      err = plonk_error("Malformed binding %s", kind);
+      break;
+    }
+
+    if (!slot_write(GSIZE_TO_POINTER(map->l_addr + strtoull(offset, NULL, 10)), value))
+      err = plonk_error("Could not bind offset %s of %s", offset, gen->path);
+    count++;
+  }
+  free(list);
+
+  if (!err)
+    plonk_log(PLONK_LOG_DEBUG, "Bound %u references of %s to the binary", count, gen->path);
+  return err;
+}
+#else
+static const char *generation_bind(struct generation *gen, const char *bindings)
+{
+  if (bindings && *bindings)
+    plonk_log(PLONK_LOG_WARN, "Sharing statics with %s is only supported on Linux", gen->path);
+  return NULL;
+}
+#endif
+
+/* Load `lib` and bind it to the binary's statics the first time, NULL on success */
+static const char *library_open(const char *lib, const char *bindings,
+                                struct generation **gen)
+{
+  unsigned count = generation_count;
+  long long start;
+
+  /* Unloaded by `collect_generations()` once unused */
+  start = now_us();
+  *gen = generation_open(lib);
+  load_us += now_us() - start;
+  if (!*gen)
+    return plonk_error("Could not open library %s: %s", lib, plonk_dlerror());
+
+  if (generation_count == count)
+    return NULL;
+  return generation_bind(*gen, bindings);
+}
+
+/* Patch comma separated lists of symbols, skipping the ones that fail */
+static const char *plonk_list(const char *syms, const char *new_syms, const char *lib,
+                              const char *bindings, const char *calls)
+{
+  char *sym_list, *new_list, *sym_rest, *new_rest, *sym;
+  const char *err, *any_err = NULL;
+  struct batch batch = { 0 };
+  struct generation *gen;
+
+  err = library_open(lib, bindings, &gen);
+  if (err)
+    return err;
+
+  sym_list = strdup(syms);
+  new_list = new_syms ? strdup(new_syms) : NULL;
+  sym_rest = sym_list;
+  new_rest = new_list;
+
+  while ((sym = next_field(&sym_rest, ',')) != NULL) {
+    err = plonk(&batch, sym, next_field(&new_rest, ','), gen, calls);
+    if (err)
+      any_err = err;
+  }
+  if (batch.len) {
+    err = apply(&batch);
+    if (err)
+      any_err = err;
+  }
+
+  batch_free(&batch);
+  free(sym_list);
+  free(new_list);
+  return any_err;
+}
+
+/* Revert the tab separated `syms`, or every patched function if NULL */
+static const char *plonk_revert(unsigned id, char *syms)
+{
+  struct batch batch = { 0 };
+  struct patch *patch;
+  const char *err = NULL;
+  char *sym;
+
+  if (!syms) {
+    for (patch = patches; patch && !err; patch = patch->next)
+      err = patch_revert(&batch, patch, id);
+  }
+  while (!err && (sym = next_field(&syms, '\t')) != NULL) {
+    patch = patch_find(find_original(sym));
+    if (!patch)
+      err = plonk_error("%s is not patched", sym);
+    else
+      err = patch_revert(&batch, patch, id);
+  }
+
+  if (!err && batch.len)
+    err = apply(&batch);
+  batch_free(&batch);
+  return err;
+}
+
+#ifdef PLONK_UNIX
+struct stack_check {
+  const GumMemoryRange *range;
+  GumBacktracer *backtracer;
+  GumThreadId self;
+  gboolean busy;
+};
+
+static gboolean in_range(const GumMemoryRange *range, gpointer address)
+{
+  GumAddress addr = GUM_ADDRESS(address);
+
+  return addr >= range->base_address && addr < range->base_address + range->size;
+}
+
+static gboolean check_thread(const GumThreadDetails *details, gpointer user_data)
+{
+  struct stack_check *check = user_data;
+  GumReturnAddressArray frames;
+  gpointer pc;
+  guint i;
+
+  if (details->id == check->self)
+    return TRUE;
+
+  pc = GSIZE_TO_POINTER(GUM_CPU_CONTEXT_XIP(&details->cpu_context));
+  /* Couldn't read its registers, assume the worst */
+  if (!pc || in_range(check->range, pc)) {
+    check->busy = TRUE;
+    return FALSE;
+  }
+
+  /* Fuzzy, so it also catches frames without frame pointers */
+  gum_backtracer_generate_with_limit(check->backtracer, &details->cpu_context, &frames,
+                                     GUM_MAX_BACKTRACE_DEPTH);
+  for (i = 0; i < frames.len; i++) {
+    if (in_range(check->range, frames.items[i])) {
+      check->busy = TRUE;
+      return FALSE;
+    }
+  }
+  return TRUE;
+}
+
+/* Whether any other thread is executing in, or will return to, `range` */
+static gboolean range_in_use(const GumMemoryRange *range)
+{
+  struct stack_check check = { range, gum_backtracer_make_fuzzy(),
+                               gum_process_get_current_thread_id(), FALSE };
+
+  gum_process_enumerate_threads(check_thread, &check);
+  g_object_unref(check.backtracer);
+  return check.busy;
+}
+
+/* Set by PLONK_UNLOAD, see `collect_generations()` */
+static int unload_generations;
+
+/*
+ * Unload superseded generations that no function runs or can be reverted to
+ * and no thread is running, appending their paths to `unloaded` as tab separated fields.
+ * The busy ones are tried again after the next patch.
+ *
+ * Only the stacks are checked, heap objects the generation's code made can
+ * still point into it, e.g. at string literals or vtables, and crash once it
+ * is gone. So this is opt-in.
+ */
+static void collect_generations(char *unloaded, size_t size)
+{
+  struct generation **link, *gen;
+  size_t len;
+  int i;
+
+  if (!generations || !unload_generations)
     return;
+
+  link = &generations->next;
+  while ((gen = *link) != NULL) {
+    /* Without its range there is no telling whether it is in use */
+    if (generation_referenced(gen) || !gen->range.size || range_in_use(&gen->range)) {
+      link = &gen->next;
+      continue;
+    }
+
+    plonk_log(PLONK_LOG_DEBUG, "Unloading %s", gen->path);
+    for (i = 0; i < gen->redirect_count; i++)
+      gum_interceptor_revert(interceptor, gen->redirects[i]);
+    plonk_dlclose(gen->handle);
+    len = strlen(unloaded);
+    snprintf(unloaded + len, size - len, "\t%s", gen->path);
+
+    *link = gen->next;
+    free(gen->path);
+    free(gen);
   }
+}
+
+static char *control_path;
 
-  /* Leak (intentional) */
-  dl = plonk_dlopen(lib);
-  if (!dl) {
-    fprintf(stderr, "[*] Could not open library %s\n", lib);
-    fprintf(stderr, "[*] Error: %s\n", plonk_dlerror());
+/*
+ * One command per connection, fields separated by tabs:
+ *
+ *   patch <library> <bindings> <calls> <symbol> <new symbol> [<symbol> <new symbol>...]
+ *   revert <generation> [<symbol>...]
+ *
+ * Replies with `ok <load us> <patch us> <generation> [<unloaded library>...]`
+ * or `error <message>`. Generation 0 is the binary's own code. Bindings and
+ * calls are the comma separated lists `generation_bind()` and
+ * `calls_queue()` take, possibly empty.
+ */
+static void control_handle(int conn)
+{
+  char buf[65536], unloaded[4096] = "", *cmd, *arg, *sym, *new_sym, *calls, *rest;
+  struct generation *gen = NULL;
+  struct batch batch = { 0 };
+  const char *err = NULL;
+  unsigned generation = 0;
+  size_t len = 0;
+  ssize_t n;
+
+  while (len < sizeof(buf) - 1) {
+    n = read(conn, buf + len, sizeof(buf) - 1 - len);
+    if (n <= 0)
+      break;
+    len += n;
+    if (memchr(buf, '\n', len))
+      break;
+  }
+  buf[len] = '\0';
+  buf[strcspn(buf, "\n")] = '\0';
+
+  load_us = patch_us = 0;
+  rest = buf;
+  cmd = next_field(&rest, '\t');
+  arg = next_field(&rest, '\t');
+
+  if (!strcmp(cmd, "patch") && arg && rest) {
+    err = library_open(arg, next_field(&rest, '\t'), &gen);
+    calls = next_field(&rest, '\t');
+    /* All or nothing */
+    while (!err && (sym = next_field(&rest, '\t')) != NULL) {
+      new_sym = next_field(&rest, '\t');
+      err = plonk(&batch, sym, new_sym, gen, calls);
+    }
+    if (!err)
+      err = apply(&batch);
+    if (!err)
+      generation = generations->id;
+    batch_free(&batch);
+  } else if (!strcmp(cmd, "revert") && arg) {
+    generation = atoi(arg);
+    err = plonk_revert(generation, rest);
+  } else {
+    err = plonk_error("Unknown command: %s", cmd);
+  }
+
+  if (err) {
+    dprintf(conn, "error\t%s\n", err);
     return;
   }
 
-  new = GSIZE_TO_POINTER (gum_module_find_export_by_name(lib, new_sym));
-  if (!new) {
-    fprintf(stderr, "[*] Could not find symbol %s in %s\n", new_sym, lib);
+  collect_generations(unloaded, sizeof(unloaded));
+  dprintf(conn, "ok\t%lld\t%lld\t%u%s\n", load_us, patch_us, generation, unloaded);
+}
+
+static void *control_thread(void *data)
+{
+  struct sockaddr_un addr;
+  int server, conn;
+
+  memset(&addr, 0, sizeof(addr));
+  addr.sun_family = AF_UNIX;
+  strncpy(addr.sun_path, control_path, sizeof(addr.sun_path) - 1);
+  unlink(control_path);
+
+  server = socket(AF_UNIX, SOCK_STREAM, 0);
+  if (server < 0 || fcntl(server, F_SETFD, FD_CLOEXEC) < 0 ||
+      bind(server, (struct sockaddr *) &addr, sizeof(addr)) < 0 ||
+      listen(server, 4) < 0) {
+    plonk_error("Could not listen on %s: %s", control_path, strerror(errno));
+    return NULL;
+  }
+
+  plonk_log(PLONK_LOG_DEBUG, "Listening on %s", control_path);
+  for (;;) {
+    conn = accept(server, NULL, NULL);
+    if (conn < 0) {
+      if (errno == EINTR)
+        continue;
+      break;
+    }
+    control_handle(conn);
+    close(conn);
+  }
+
+  close(server);
+  return NULL;
+}
+
+/* Accept new patches from plonk while the program runs */
+static void control_start(const char *path)
+{
+  pthread_t thread;
+
+  control_path = strdup(path);
+  /* The socket belongs to this process, not to the ones it spawns */
+  plonk_unsetenv("PLONK_CONTROL");
+
+  if (pthread_create(&thread, NULL, control_thread, NULL)) {
+    plonk_error("Could not start the control thread");
     return;
   }
+  pthread_detach(thread);
+}
+#endif
 
-  if (new == original) {
-    fprintf(stderr, "[*] New symbol %s is the same as the original\n", new_sym);
+/* Tell plonk how long patching at startup took, see `plonk_timings` */
+static void report_timings(const char *path)
+{
+  FILE *report;
+
+  report = fopen(path, "w");
+  if (!report) {
+    plonk_log(PLONK_LOG_WARN, "Could not write timings to %s", path);
     return;
   }
+  fprintf(report, "%lld\t%lld\n", load_us, patch_us);
+  fclose(report);
+}
 
-  if (verbose) {
-    printf("[*] Plonking %s in %s\n", sym, lib);
-    printf("[*] Old address: %p\n", original);
-    printf("[*] New address: %p\n", new);
+__attribute__((constructor))
+static void init()
+{
+  char *proto, *sym, *new_sym, *lib, *bindings, *calls, *bin, *control, *timings, *scrub_env;
+
+  proto = plonk_getenv("PLONK_PROTOCOL");
+  if (!proto)
+    return;
+
+  sym = plonk_getenv("PLONK_SYMBOL");
+  new_sym = plonk_getenv("PLONK_NEW_SYMBOL");
+
+  /* Library with the new symbols */
+  lib = plonk_getenv("PLONK_LIBRARY");
+  /* References of the library to point at the binary's statics */
+  bindings = plonk_getenv("PLONK_BIND");
+  /* Call sites of the functions too small to hook */
+  calls = plonk_getenv("PLONK_CALLS");
+  bin = plonk_getenv("PLONK_BINARY");
+  control = plonk_getenv("PLONK_CONTROL");
+  timings = plonk_getenv("PLONK_TIMINGS");
+  stop_world = plonk_getenv("PLONK_STOP_WORLD") != NULL;
+  unload_generations = plonk_getenv("PLONK_UNLOAD") != NULL;
+  scrub_env = plonk_getenv("PLONK_SCRUB");
+
+  log_init();
+
+  if (atoi(proto) != PLONK_PROTOCOL_VERSION) {
+    plonk_log(PLONK_LOG_ERROR, "Unsupported plonk protocol %s (expected %d)", proto,
+              PLONK_PROTOCOL_VERSION);
+    plonk_log(PLONK_LOG_ERROR, "The injector and cargo-plonk are out of sync");
+  } else {
+    gum_init_embedded();
+    interceptor = gum_interceptor_obtain();
+    if (bin)
+      bin_path = strdup(bin);
+    if (stop_world)
+      world_init();
+
+    if (sym && lib)
+      plonk_list(sym, new_sym, lib, bindings, calls);
+    if (timings)
+      report_timings(timings);
+#ifdef PLONK_UNIX
+    if (control)
+      control_start(control);
+#endif
   }
 
-  gum_interceptor_replace_fast(interceptor, original, new, NULL);
-  if (verbose)
-    printf("===\n");
+  if (scrub_env)
+    scrub();
 }
@@ -26,6 +26,7 @@ use std::mem::size_of;
 use std::os::windows::ffi::OsStrExt;
 use std::os::windows::io::AsRawHandle;
 use std::process::Command;
+use std::process::ExitStatus;
 
 use dynasmrt::dynasm;
 use dynasmrt::DynasmApi;
@@ -36,7 +37,7 @@ use winapi::um::winbase::INFINITE;
 use winapi::um::winbase::WAIT_FAILED;
 
 /// This uses the `CreateRemoteThread` technique to inject a DLL into the process.
-pub unsafe fn inject(command: &mut Command, dll_path: &str) {
+pub unsafe fn inject(command: &mut Command, dll_path: &str) -> ExitStatus {
     let mut process = command.spawn().unwrap();
     let process_handle = process.as_raw_handle();
 
@@ -89,7 +90,7 @@ pub unsafe fn inject(command: &mut Command, dll_path: &str) {
 
     let code = ops.finalize().unwrap();
 
-    println!("Code compiled");
+    plonk_log!(Debug, "Code compiled");
     let code_alloc = alloc_remote(process_handle, code.len()).unwrap();
 
     write_process_memory(process_handle, code_alloc, &code).unwrap();
@@ -121,7 +122,7 @@ pub unsafe fn inject(command: &mut Command, dll_path: &str) {
 
     let reason = WaitForSingleObject(thread_handle, INFINITE);
     if reason == WAIT_FAILED {
-        println!("{}", std::io::Error::last_os_error());
+        plonk_log!(Error, "{}", std::io::Error::last_os_error());
         panic!("Failed to wait for remote thread");
     }
 
@@ -143,13 +144,13 @@ pub unsafe fn inject(command: &mut Command, dll_path: &str) {
 
     if exit_code != 0 {
         if exit_code == 0xc0000005 {
-            println!("Exit code: (Access violation)");
+            plonk_log!(Error, "Exit code: (Access violation)");
         } else {
-            println!("{}", std::io::Error::from_raw_os_error(exit_code as i32));
+            plonk_log!(Error, "{}", std::io::Error::from_raw_os_error(exit_code as i32));
         }
     }
 
-    process.wait().unwrap();
+    process.wait().unwrap()
 }
 
 fn alloc_remote(
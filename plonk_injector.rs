@@ -0,0 +1,115 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* The injector, embedded in plonk and extracted where the loader can find it */
+
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::private_temp_dir;
+
+/// The injector build.rs compiled.
+const INJECTOR: &[u8] = include_bytes!(env!("PLONK_INJECT_DYLIB"));
+/// Its SHA-256, hex encoded.
+const SHA256: &str = env!("PLONK_INJECT_SHA256");
+
+#[cfg(target_os = "windows")]
+const FILE_NAME: &str = "inject.dll";
+#[cfg(not(target_os = "windows"))]
+const FILE_NAME: &str = "inject.dylib";
+
+/// Write the injector to a cache directory named after this plonk's version
+/// and the injector's checksum, so plonks of other versions don't overwrite
+/// each other's, and return its path. A copy already there is used if its
+/// checksum matches, otherwise it is replaced.
+pub fn extract() -> Result<String, String> {
+    let dir = cache_dir()?.join(format!("{}-{}", env!("CARGO_PKG_VERSION"), &SHA256[..16]));
+    let path = dir.join(FILE_NAME);
+
+    match std::fs::read(&path) {
+        Ok(data) if sha256(&data) == SHA256 => return Ok(path.to_string_lossy().into_owned()),
+        Ok(_) => plonk_log!(
+            Warn,
+            "{} doesn't match the injector plonk was built with, replacing it",
+            path.display()
+        ),
+        Err(_) => {}
+    }
+
+    if sha256(INJECTOR) != SHA256 {
+        return Err("The injector embedded in plonk is corrupt, reinstall plonk".into());
+    }
+    write(&dir, &path).map_err(|err| {
+        format!(
+            "Failed to write the injector to {}: {}",
+            path.display(),
+            err
+        )
+    })?;
+    plonk_log!(Debug, "Extracted the injector to {}", path.display());
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Write through a temporary file and rename it, so a program starting
+/// meanwhile, or another plonk extracting too, never loads half of it.
+fn write(dir: &Path, path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let temp = dir.join(format!("{}.{}", FILE_NAME, std::process::id()));
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(INJECTOR)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp);
+    })
+}
+
+/// `plonk` in the user's cache directory, or a directory of our own in the
+/// temporary directory without one.
+fn cache_dir() -> Result<PathBuf, String> {
+    #[cfg(target_os = "windows")]
+    let base = std::env::var_os("LOCALAPPDATA").map(PathBuf::from);
+    #[cfg(target_os = "macos")]
+    let base = std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Caches"));
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")));
+
+    match base {
+        Some(base) => Ok(base.join("plonk")),
+        // Other users could swap the injector between its checksum check and
+        // the program loading it in a shared one.
+        None => private_temp_dir().map_err(|err| {
+            format!("{}, refusing to put the injector there. Set HOME or XDG_CACHE_HOME", err)
+        }),
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
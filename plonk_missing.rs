@@ -0,0 +1,243 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Explaining why a `--symbol` isn't where plonk looks for it */
+
+use std::path::Path;
+
+use cargo_metadata::MetadataCommand;
+
+use crate::find_symbol;
+use crate::function_symbols;
+use crate::plonk_dwarf;
+
+/// How many similar functions to suggest.
+const SUGGESTIONS: usize = 3;
+
+/// Log why `symbol` of `package` wasn't found in `bin`, or in the library at
+/// `library_path` built from the current code, and what to do about it.
+pub fn explain(package: &str, symbol: &str, bin: &str, library_path: Option<&str>) {
+    let path = format!("{}::{}", package, symbol);
+    let bin_functions = function_symbols(bin);
+    let lib_functions = library_path.map(function_symbols).unwrap_or_default();
+    let in_bin = find_symbol(bin, package, symbol).is_some();
+    let in_lib = library_path.is_some_and(|lib| find_symbol(lib, package, symbol).is_some());
+
+    if bin_functions.is_empty() {
+        plonk_log!(
+            Error,
+            "{} has no symbol table, set `strip = false` for its profile and rebuild it",
+            bin
+        );
+        return;
+    }
+
+    match (in_bin, in_lib, library_path) {
+        (false, true, _) => plonk_log!(
+            Error,
+            "`{}` is new, the program was built before it existed. Restart it to pick it up, \
+             `--supervise` does so on its own, or patch a function it already has that calls it",
+            path
+        ),
+        (true, false, Some(lib)) => explain_library(package, symbol, &path, lib),
+        _ => explain_binary(package, symbol, &path, bin),
+    }
+
+    let candidates = bin_functions.iter().chain(&lib_functions);
+    let suggestions = suggest(package, symbol, candidates);
+    if !suggestions.is_empty() {
+        plonk_log!(
+            Error,
+            "Similar functions: {}, pass one of them to `-s`",
+            suggestions
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+/// In the binary but not in the library.
+fn explain_library(package: &str, symbol: &str, path: &str, library_path: &str) {
+    if let Some(call) = plonk_dwarf::inlined_path(library_path, path)
+        .unwrap_or_default()
+        .first()
+    {
+        plonk_log!(
+            Error,
+            "`{}` is inlined into `{:#}` in the library and left out, mark it \
+             `#[inline(never)]` to patch it",
+            path,
+            rustc_demangle::demangle(&call.caller)
+        );
+    } else if is_generic(package, symbol) {
+        plonk_log!(
+            Error,
+            "`{}` is generic and {} only has the instances the binary's crates asked for, patch \
+             the function calling it with concrete types, or a non-generic function it calls",
+            path,
+            package
+        );
+    } else {
+        plonk_log!(
+            Error,
+            "`{}` is in the running program but not in the current code, it was removed or \
+             renamed. Pass its new name to `-s`, or restart to drop it",
+            path
+        );
+    }
+}
+
+/// Not in the binary.
+fn explain_binary(package: &str, symbol: &str, path: &str, bin: &str) {
+    let inlined = plonk_dwarf::inlined_path(bin, path).unwrap_or_default();
+    if let Some(call) = inlined.first() {
+        let location = match &call.location {
+            Some(location) => format!(" ({})", location),
+            None => String::new(),
+        };
+        plonk_log!(
+            Error,
+            "`{}` was inlined into every caller, like `{:#}`{}, so {} has no copy of its own. \
+             Mark it `#[inline(never)]` and rebuild the binary, or patch its callers instead",
+            path,
+            rustc_demangle::demangle(&call.caller),
+            location,
+            bin
+        );
+    } else if is_generic(package, symbol) {
+        plonk_log!(
+            Error,
+            "`{}` is generic and nothing in {} calls it with concrete types, so it was never \
+             compiled. Patch one of the functions that call it once the binary does",
+            path,
+            bin
+        );
+    } else {
+        plonk_log!(
+            Error,
+            "{} has no `{}`, check `-p` and `-s`. Functions the program never calls can be left \
+             out by the linker, and a binary built without debug info doesn't say when the \
+             function was inlined",
+            bin,
+            path
+        );
+    }
+}
+
+/// Functions of `package` among `symbols` with a name close to `symbol`, as
+/// `-s` takes them, closest first.
+fn suggest<'a>(
+    package: &str,
+    symbol: &str,
+    symbols: impl Iterator<Item = &'a String>,
+) -> Vec<String> {
+    let prefix = format!("{}::", package);
+    // A typo is a letter or two off, anything further is another function.
+    let cutoff = (symbol.chars().count() / 3).max(1);
+    let mut scored = symbols
+        .filter_map(|name| {
+            let path = format!("{:#}", rustc_demangle::demangle(name));
+            // `#[no_mangle]` and `extern "C"` functions go by their bare name.
+            let path = match path.strip_prefix(&prefix) {
+                Some(path) => path.to_string(),
+                None if !path.contains("::") => path,
+                None => return None,
+            };
+            // Closures and other compiler made functions can't be named.
+            if path.contains('{') || path.len().abs_diff(symbol.len()) > cutoff {
+                return None;
+            }
+            let distance = edit_distance(&path, symbol);
+            (distance <= cutoff).then_some((distance, path))
+        })
+        .collect::<Vec<_>>();
+    scored.sort();
+    scored.dedup_by(|a, b| a.1 == b.1);
+
+    let mut suggestions = Vec::new();
+    for (_, path) in scored {
+        if path != symbol && !suggestions.contains(&path) {
+            suggestions.push(path);
+        }
+        if suggestions.len() == SUGGESTIONS {
+            break;
+        }
+    }
+    suggestions
+}
+
+/// Levenshtein distance between `a` and `b`, counting two swapped letters
+/// as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // `rows[i][j]` is the distance between the first `i` letters of `a` and
+    // the first `j` of `b`.
+    let mut rows = (0..=a.len())
+        .map(|i| (0..=b.len()).map(|j| if i == 0 { j } else { i }).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitute = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitute.min(rows[i - 1][j] + 1).min(rows[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Whether the source of `package` declares `symbol` with type parameters.
+fn is_generic(package: &str, symbol: &str) -> bool {
+    let meta = match MetadataCommand::new().no_deps().exec() {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    let name = symbol.rsplit("::").next().unwrap_or(symbol);
+    let declaration = format!("fn {}<", name);
+    meta.packages
+        .iter()
+        .filter(|pkg| pkg.name == package || pkg.name.replace('-', "_") == package)
+        .filter_map(|pkg| pkg.manifest_path.parent())
+        .any(|dir| declares(dir.join("src").as_std_path(), &declaration))
+}
+
+/// Whether a `.rs` file under `dir` contains `declaration`.
+fn declares(dir: &Path, declaration: &str) -> bool {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            declares(&path, declaration)
+        } else {
+            path.extension().is_some_and(|ext| ext == "rs")
+                && std::fs::read_to_string(&path).is_ok_and(|source| source.contains(declaration))
+        }
+    })
+}
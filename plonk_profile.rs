@@ -0,0 +1,448 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Cargo profile settings of the binary and the patch library */
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::rustc;
+
+/// The settings of a cargo profile, as applied to one package, that decide
+/// whether patched code fits the binary.
+pub struct Profile {
+    /// `dev`, `release` or a custom profile.
+    pub name: String,
+    /// `unwind` or `abort`.
+    pub panic: String,
+    /// `false`, `thin` or `fat`.
+    pub lto: String,
+    /// `none`, `debuginfo` or `symbols`.
+    pub strip: String,
+    pub overflow_checks: bool,
+    pub debug_assertions: bool,
+}
+
+impl Profile {
+    /// Effective profile `name` of `package` from `CARGO_PROFILE_*`
+    /// variables, cargo's config files and the workspace manifest, and the
+    /// profiles it inherits from, on top of cargo's defaults.
+    pub fn load(name: &str, package: &str) -> Result<Self, String> {
+        let manifest = workspace_manifest()?;
+        let text = std::fs::read_to_string(&manifest)
+            .map_err(|err| format!("Failed to read {}: {}", manifest.display(), err))?;
+        let manifest: toml::Table = text
+            .parse()
+            .map_err(|err| format!("Failed to parse {}: {}", manifest.display(), err))?;
+        let config = cargo_config();
+
+        // The config files take precedence over the manifest.
+        let sources = [&config, &manifest];
+        let get = |path: &[&str]| sources.iter().find_map(|source| lookup(source, path));
+        let chain = inherited(name, &get)?;
+        let release = chain.last().is_some_and(|root| root == "release");
+
+        let setting = |key: &str| -> Option<toml::Value> {
+            for profile in &chain {
+                // Per package overrides, only some settings may be set there.
+                for package in [package, "*"] {
+                    if let Some(value) = get(&["profile", profile, "package", package, key]) {
+                        return Some(value.clone());
+                    }
+                }
+                let var = format!(
+                    "CARGO_PROFILE_{}_{}",
+                    profile.replace('-', "_").to_uppercase(),
+                    key.replace('-', "_").to_uppercase()
+                );
+                if let Ok(value) = std::env::var(var) {
+                    return Some(match value.as_str() {
+                        "true" => toml::Value::Boolean(true),
+                        "false" => toml::Value::Boolean(false),
+                        _ => toml::Value::String(value),
+                    });
+                }
+                if let Some(value) = get(&["profile", profile, key]) {
+                    return Some(value.clone());
+                }
+            }
+            None
+        };
+        let flag = |key: &str, default: bool| match setting(key) {
+            Some(toml::Value::Boolean(value)) => value,
+            _ => default,
+        };
+        let choice = |key: &str, off: &str, on: &str| match setting(key) {
+            Some(toml::Value::Boolean(false)) => off.to_string(),
+            Some(toml::Value::Boolean(true)) => on.to_string(),
+            Some(toml::Value::String(value)) if value == "off" => off.to_string(),
+            Some(toml::Value::String(value)) => value,
+            _ => off.to_string(),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            panic: choice("panic", "unwind", "unwind"),
+            lto: choice("lto", "false", "fat"),
+            strip: choice("strip", "none", "symbols"),
+            overflow_checks: flag("overflow-checks", !release),
+            debug_assertions: flag("debug-assertions", !release),
+        })
+    }
+}
+
+/// `path` in `table`, through nested tables.
+fn lookup<'a>(table: &'a toml::Table, path: &[&str]) -> Option<&'a toml::Value> {
+    let (last, tables) = path.split_last()?;
+    tables
+        .iter()
+        .try_fold(table, |table, key| table.get(*key)?.as_table())?
+        .get(*last)
+}
+
+/// `name` and the profiles it inherits from, down to `dev` or `release`.
+fn inherited<'a>(
+    name: &str,
+    get: &impl Fn(&[&str]) -> Option<&'a toml::Value>,
+) -> Result<Vec<String>, String> {
+    let mut chain = vec![name.to_string()];
+    loop {
+        let profile = chain.last().unwrap();
+        let parent = match profile.as_str() {
+            "dev" | "release" => return Ok(chain),
+            "test" => "dev",
+            "bench" => "release",
+            _ => get(&["profile", profile, "inherits"])
+                .and_then(|inherits| inherits.as_str())
+                .ok_or_else(|| {
+                    format!("Profile `{}` doesn't say which profile it inherits", profile)
+                })?,
+        };
+        if chain.iter().any(|profile| profile == parent) {
+            return Err(format!("Profile `{}` inherits from itself", name));
+        }
+        chain.push(parent.to_string());
+    }
+}
+
+/// Cargo's config files merged the way cargo does, without the environment:
+/// `.cargo/config.toml` in the current directory and its parents, then in
+/// `$CARGO_HOME`. Nearer files win, lists are joined. Files that can't be
+/// read are skipped with a warning, they only refine the manifest.
+fn cargo_config() -> toml::Table {
+    let mut dirs = std::env::current_dir()
+        .map(|dir| dir.ancestors().map(|dir| dir.join(".cargo")).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let home = std::env::var_os("CARGO_HOME").map(PathBuf::from).or_else(|| {
+        std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".cargo"))
+    });
+    if let Some(home) = home.filter(|home| !dirs.contains(home)) {
+        dirs.push(home);
+    }
+
+    let mut config = toml::Table::new();
+    for dir in dirs.iter().rev() {
+        // `config` is the name from before cargo 1.39.
+        let file = match ["config.toml", "config"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|file| file.is_file())
+        {
+            Some(file) => file,
+            None => continue,
+        };
+        let parsed = std::fs::read_to_string(&file)
+            .map_err(|err| err.to_string())
+            .and_then(|text| text.parse::<toml::Table>().map_err(|err| err.to_string()));
+        match parsed {
+            Ok(table) => merge(&mut config, table),
+            Err(err) => plonk_log!(Warn, "Ignoring {}: {}", file.display(), err),
+        }
+    }
+    config
+}
+
+/// Merge `nearer` into `config` like cargo merges config files.
+fn merge(config: &mut toml::Table, nearer: toml::Table) {
+    for (key, value) in nearer {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(nearer)) => merge(table, nearer),
+            (Some(toml::Value::Array(list)), toml::Value::Array(nearer)) => list.extend(nearer),
+            (_, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The flags cargo passes to rustc for the host, from the first of
+/// `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`, the `[target]` tables matching
+/// the host and `build.rustflags` that has any, like cargo picks them.
+pub fn rustflags() -> Result<Vec<String>, String> {
+    if let Ok(encoded) = std::env::var("CARGO_ENCODED_RUSTFLAGS") {
+        return Ok(encoded
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .map(String::from)
+            .collect());
+    }
+    if let Ok(flags) = std::env::var("RUSTFLAGS") {
+        return Ok(flags.split_whitespace().map(String::from).collect());
+    }
+
+    let config = cargo_config();
+    let host = rustc_print(&["-vV"])?
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(String::from)
+        .ok_or("rustc didn't say what the host is")?;
+    let cfgs = rustc_print(&["--print", "cfg"])?
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+
+    let mut flags = Vec::new();
+    let targets = config.get("target").and_then(|targets| targets.as_table());
+    for (target, table) in targets.into_iter().flatten() {
+        let matches = match target.strip_prefix("cfg(") {
+            Some(cfg) => cfg_matches(cfg.strip_suffix(')').unwrap_or(cfg), &cfgs),
+            None => *target == host,
+        };
+        if matches {
+            flags.extend(flag_list(table.get("rustflags")));
+        }
+    }
+    let var = format!(
+        "CARGO_TARGET_{}_RUSTFLAGS",
+        host.replace(['-', '.'], "_").to_uppercase()
+    );
+    if let Ok(env) = std::env::var(var) {
+        flags.extend(env.split_whitespace().map(String::from));
+    }
+    if !flags.is_empty() {
+        return Ok(flags);
+    }
+
+    flags.extend(flag_list(lookup(&config, &["build", "rustflags"])));
+    if let Ok(env) = std::env::var("CARGO_BUILD_RUSTFLAGS") {
+        flags.extend(env.split_whitespace().map(String::from));
+    }
+    Ok(flags)
+}
+
+/// A `rustflags` setting, a list or a space separated string.
+fn flag_list(value: Option<&toml::Value>) -> Vec<String> {
+    match value {
+        Some(toml::Value::String(flags)) => flags.split_whitespace().map(String::from).collect(),
+        Some(toml::Value::Array(flags)) => flags
+            .iter()
+            .filter_map(|flag| flag.as_str())
+            .map(String::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn rustc_print(args: &[&str]) -> Result<String, String> {
+    let output = rustc()
+        .args(args)
+        .output()
+        .map_err(|err| format!("Failed to run rustc: {}", err))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Whether the `cfg(...)` predicate `expr` holds for `cfgs`, the lines of
+/// `rustc --print cfg`.
+fn cfg_matches(expr: &str, cfgs: &[String]) -> bool {
+    let expr = expr.trim();
+    for (op, all) in [("all(", true), ("any(", false)] {
+        if let Some(inner) = expr.strip_prefix(op).and_then(|rest| rest.strip_suffix(')')) {
+            let mut parts = cfg_split(inner).into_iter().filter(|part| !part.trim().is_empty());
+            return if all {
+                parts.all(|part| cfg_matches(part, cfgs))
+            } else {
+                parts.any(|part| cfg_matches(part, cfgs))
+            };
+        }
+    }
+    if let Some(inner) = expr.strip_prefix("not(").and_then(|rest| rest.strip_suffix(')')) {
+        return !cfg_matches(inner, cfgs);
+    }
+    // `key` or `key = "value"`, printed without the spaces.
+    let cfg = match expr.split_once('=') {
+        Some((key, value)) => format!("{}={}", key.trim(), value.trim()),
+        None => expr.to_string(),
+    };
+    cfgs.contains(&cfg)
+}
+
+/// Split a `cfg` predicate list at its top level commas.
+fn cfg_split(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (at, c) in list.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&list[start..at]);
+                start = at + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&list[start..]);
+    parts
+}
+
+/// The manifest of the workspace the current directory is in.
+fn workspace_manifest() -> Result<PathBuf, String> {
+    let dir = std::env::current_dir().map_err(|err| format!("No current directory: {}", err))?;
+    let manifests = dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.toml"))
+        .filter(|manifest| manifest.is_file())
+        .collect::<Vec<_>>();
+    // The nearest one with a `[workspace]`, or a package on its own.
+    manifests
+        .iter()
+        .find(|manifest| is_workspace(manifest))
+        .or(manifests.first())
+        .cloned()
+        .ok_or_else(|| format!("No Cargo.toml in {} or above", dir.display()))
+}
+
+fn is_workspace(manifest: &Path) -> bool {
+    std::fs::read_to_string(manifest)
+        .ok()
+        .and_then(|text| text.parse::<toml::Table>().ok())
+        .is_some_and(|manifest| manifest.contains_key("workspace"))
+}
+
+/// Settings the library has to be built with to load into the binary at
+/// all, as `cargo --config` arguments.
+pub fn library_config(profile: &Profile) -> Vec<String> {
+    let mut config = Vec::new();
+    // libstd is built to unwind, a library linking it can't abort.
+    if profile.panic == "abort" {
+        config.push(format!("profile.{}.panic=\"unwind\"", profile.name));
+    }
+    // plonk looks up the new functions by name.
+    if profile.strip == "symbols" {
+        config.push(format!("profile.{}.strip=false", profile.name));
+    }
+    config
+}
+
+/// `cargo --config` argument that builds `package` without optimizations,
+/// so it calls functions of other crates instead of inlining them.
+pub fn no_inline(profile: &str, package: &str) -> String {
+    format!("profile.{}.package.\"{}\".opt-level=0", profile, package)
+}
+
+/// Directory under `target/` cargo puts the builds of `profile` in.
+pub fn dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// `cargo build` arguments selecting `profile`, for messages.
+pub fn args(profile: &str) -> String {
+    match profile {
+        "dev" => String::new(),
+        "release" => " --release".into(),
+        profile => format!(" --profile {}", profile),
+    }
+}
+
+/// Check that patches built for `package` can work in `bin`, built from
+/// `bin_package`. Fails for settings that keep patching from working at all
+/// and returns warnings for ones that change how patched code behaves.
+#[cfg(not(target_os = "windows"))]
+pub fn check(
+    bin: &str,
+    bin_package: &str,
+    package: &str,
+    profile: &str,
+) -> Result<Vec<String>, String> {
+    let ours = Profile::load(profile, package)?;
+    let theirs = Profile::load(profile, bin_package)?;
+
+    let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+    let file =
+        object::File::parse(&*data).map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+    if object::Object::symbols(&file).next().is_none() {
+        return Err(format!(
+            "{} has no symbols (`strip = \"{}\"`), set `strip = false` in [profile.{}] so plonk \
+             can find its functions",
+            bin, theirs.strip, theirs.name
+        ));
+    }
+    if theirs.lto != "false" {
+        return Err(format!(
+            "{} is built with `lto = \"{}\"`, which inlines and merges the functions plonk \
+             patches, set `lto = false` in [profile.{}]",
+            bin, theirs.lto, theirs.name
+        ));
+    }
+
+    let mut warnings = Vec::new();
+    if theirs.panic == "abort" {
+        warnings.push(format!(
+            "{} is built with `panic = \"abort\"`, a panic in patched code can't unwind into it \
+             and aborts the program",
+            bin
+        ));
+    }
+    for (setting, ours, theirs) in [
+        (
+            "overflow-checks",
+            ours.overflow_checks,
+            theirs.overflow_checks,
+        ),
+        (
+            "debug-assertions",
+            ours.debug_assertions,
+            theirs.debug_assertions,
+        ),
+    ] {
+        if ours != theirs {
+            let state = |on: bool| if on { "on" } else { "off" };
+            warnings.push(format!(
+                "{} is built with `{}` {} and {} with it {}, generic functions of {} the binary \
+                 used behave differently once patched",
+                package,
+                setting,
+                state(ours),
+                bin_package,
+                state(theirs),
+                package
+            ));
+        }
+    }
+    Ok(warnings)
+}
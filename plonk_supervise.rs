@@ -0,0 +1,671 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Restart-on-change supervision for `run --watch --supervise` */
+
+use std::collections::BTreeSet;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+
+use cargo_metadata::MetadataCommand;
+
+use crate::build;
+use crate::command;
+use crate::exit_with;
+use crate::find_bin;
+use crate::find_symbol;
+use crate::forward_lines;
+use crate::function_symbols;
+use crate::plonk_bind;
+use crate::plonk_calls;
+use crate::plonk_child;
+use crate::plonk_dwarf;
+use crate::plonk_generations;
+use crate::plonk_missing;
+use crate::plonk_profile;
+use crate::plonk_timings;
+use crate::plonk_toolchain;
+use crate::plonk_tui::Dashboard;
+use crate::plonk_tui::Pick;
+use crate::plonk_tui::State;
+use crate::poll_changes;
+use crate::private_temp_dir;
+use crate::resolve_symbols;
+use crate::tap_stderr;
+use crate::tool_stderr;
+use crate::warn_inlined;
+use crate::watcher;
+use crate::Options;
+
+/// Outcome of a change.
+enum Reload {
+    /// The new code was patched into the running program.
+    Patched,
+    /// The change can't be patched in place.
+    Restart(String),
+    /// Nothing to do until the next change, e.g. a compile error.
+    Skip,
+}
+
+struct Session {
+    bin_package: String,
+    bin: String,
+    /// Package being hot patched.
+    package: Option<String>,
+    /// Local packages and their directories.
+    packages: Vec<(String, PathBuf)>,
+    /// Socket the injector listens on for new patches.
+    control: PathBuf,
+    signal: libc::c_int,
+    timeout: Duration,
+    /// Generation of the last patch, as numbered by the injector.
+    generation: u32,
+    /// Packages built without inlining for `--no-inline`.
+    no_inline: BTreeSet<String>,
+    dashboard: Option<Arc<Dashboard>>,
+}
+
+impl Session {
+    fn show(&self, f: impl FnOnce(&mut State)) {
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.update(f);
+        }
+    }
+}
+
+pub fn supervise(pargs: &mut Options, dashboard: Option<Arc<Dashboard>>) -> Option<ExitStatus> {
+    let signal_name = pargs.restart_signal.as_deref().unwrap_or("TERM");
+    let signal = match plonk_child::parse_signal(signal_name) {
+        Some(signal) => signal,
+        None => {
+            plonk_log!(Error, "Unknown signal: {}", signal_name);
+            return None;
+        }
+    };
+
+    let control = match control_path(std::process::id()) {
+        Ok(control) => control,
+        Err(err) => {
+            plonk_log!(Error, "No place for the control socket: {}", err);
+            return None;
+        }
+    };
+
+    let (bin_package, bin) = find_bin(pargs)?;
+    let meta = MetadataCommand::new()
+        .exec()
+        .expect("Failed to get metadata");
+    let packages = meta
+        .packages
+        .iter()
+        .filter(|pkg| pkg.source.is_none())
+        .map(|pkg| {
+            let mut dir = pkg.manifest_path.clone();
+            dir.pop();
+            (pkg.name.clone(), dir.into_std_path_buf())
+        })
+        .collect::<Vec<_>>();
+    let package = if pargs.package == "." {
+        let cwd = std::env::current_dir().expect("Failed to get current directory");
+        package_of(&cwd, &packages).map(String::from)
+    } else {
+        Some(pargs.package.clone())
+    };
+
+    let mut session = Session {
+        bin_package,
+        bin,
+        package,
+        packages,
+        control,
+        signal,
+        timeout: Duration::from_secs(pargs.restart_timeout.unwrap_or(5)),
+        generation: 0,
+        no_inline: BTreeSet::new(),
+        dashboard,
+    };
+    session.show(|state| state.symbols = pargs.symbols.clone());
+
+    let _debouncer = watcher(pargs);
+    pargs._internal_meta = true;
+    // Lets `cargo plonk revert` find us.
+    if let Err(err) = pargs.watch_cache.generations.open() {
+        plonk_log!(Error, "Failed to create the session directory: {}", err);
+        return None;
+    }
+
+    plonk_log!(Info, "Starting {}", session.bin);
+    let mut child = start(pargs, &mut session);
+    plonk_timings::finish(false);
+    loop {
+        let changed = next_change(pargs, &mut child, &session);
+        plonk_timings::start(pargs.watch_cache.changed_at.take());
+        let reloaded = reload(pargs, &mut session, &changed, child.is_some());
+        let patched = matches!(reloaded, Reload::Patched);
+        match reloaded {
+            Reload::Patched => {}
+            Reload::Skip => plonk_log!(Info, "Waiting for changes"),
+            Reload::Restart(reason) => {
+                plonk_log!(Info, "Restarting {}: {}", session.bin, reason);
+                if let Some(mut child) = child.take() {
+                    plonk_child::stop(&mut child, session.signal, session.timeout)
+                        .expect("Failed to stop bin");
+                }
+                child = start(pargs, &mut session);
+            }
+        }
+        plonk_timings::finish(patched);
+    }
+}
+
+/// Build the binary and start it with the injector listening for patches.
+fn start(pargs: &mut Options, session: &mut Session) -> Option<Child> {
+    let bin_name = Path::new(&session.bin).file_name().unwrap();
+    let mut cargo = Command::new("cargo");
+    cargo
+        .arg("build")
+        .arg("-p")
+        .arg(&session.bin_package)
+        .arg("--bin")
+        .arg(bin_name);
+    cargo.arg("--profile").arg(&pargs.profile);
+    for package in &session.no_inline {
+        cargo
+            .arg("--config")
+            .arg(plonk_profile::no_inline(&pargs.profile, package));
+    }
+
+    session.show(|state| state.phase = "Building".into());
+    let started = Instant::now();
+    let mut build = cargo
+        .stderr(tool_stderr())
+        .spawn()
+        .expect("Failed to spawn cargo build");
+    tap_stderr(&mut build);
+    let status = build.wait().expect("Failed to wait for cargo build");
+    if !status.success() {
+        plonk_log!(Error, "Failed to build {}", session.bin_package);
+        session.show(|state| state.phase = "Build failed".into());
+        return None;
+    }
+    plonk_timings::phase("compile", started.elapsed());
+
+    let checked = plonk_profile::check(
+        &session.bin,
+        &session.bin_package,
+        &pargs.package,
+        &pargs.profile,
+    );
+    match checked {
+        Ok(warnings) => {
+            for warning in warnings {
+                plonk_log!(Warn, "{}", warning);
+            }
+        }
+        Err(err) => {
+            plonk_log!(Error, "Refusing to patch, {}", err);
+            session.show(|state| state.phase = "Can't patch".into());
+            return None;
+        }
+    }
+
+    // Symbols moved, look them up again.
+    pargs.watch_cache.bin_symbols.clear();
+    pargs.watch_cache.bin_signatures.clear();
+    pargs.watch_cache.bin_statics = None;
+    pargs.watch_cache.bin_inlined.clear();
+    pargs.watch_cache.inlined_warned.clear();
+    pargs.watch_cache.bin_functions = None;
+    pargs.watch_cache.bin_toolchain = None;
+    // None of the patches made it into the new process.
+    pargs.watch_cache.generations.collect(0);
+    session.generation = 0;
+
+    let _ = std::fs::remove_file(&session.control);
+    let mut lib = command(pargs, &session.bin);
+    lib.env("PLONK_CONTROL", &session.control);
+    if session.dashboard.is_some() {
+        lib.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+
+    plonk_log!(Debug, "Running: {:?}", lib);
+    let mut child = match plonk_child::spawn(&mut lib, session.dashboard.is_none()) {
+        Ok(child) => child,
+        Err(err) => {
+            plonk_log!(Error, "Failed to spawn binary {}: {}", session.bin, err);
+            return None;
+        }
+    };
+
+    if let Some(dashboard) = &session.dashboard {
+        for stream in [
+            child.stdout.take().map(|out| Box::new(out) as Box<dyn std::io::Read + Send>),
+            child.stderr.take().map(|err| Box::new(err) as _),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let dashboard = dashboard.clone();
+            forward_lines(stream, move |line| dashboard.update(|state| state.output(line)));
+        }
+
+        let candidates = candidates(&session.bin, session.package.as_deref());
+        let build_time = started.elapsed();
+        dashboard.update(|state| {
+            state.phase = "Running".into();
+            state.build_time = Some(build_time);
+            state.generation = 0;
+            state.patched.clear();
+            state.candidates = candidates;
+        });
+    }
+
+    Some(child)
+}
+
+/// Functions of the binary that can be picked from the dashboard, as accepted
+/// by `--symbol`.
+fn candidates(bin: &str, package: Option<&str>) -> Vec<String> {
+    let prefix = format!("{}::", package.unwrap_or_default().replace('-', "_"));
+    let mut names = function_symbols(bin)
+        .into_iter()
+        .filter_map(|name| {
+            // `{:#}` leaves out the hash.
+            let demangled = format!("{:#}", rustc_demangle::demangle(&name));
+            if demangled != name {
+                return demangled.strip_prefix(&prefix).map(String::from);
+            }
+            // Not mangled, e.g. `#[no_mangle]` or C functions.
+            #[cfg(target_os = "macos")]
+            let name = name.strip_prefix('_').map(String::from).unwrap_or(name);
+            Some(name)
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Block until some watched file changes, reaping the child if it exits
+/// meanwhile. Symbols picked in the dashboard return no changes, so that
+/// the current code gets patched in.
+fn next_change(pargs: &mut Options, child: &mut Option<Child>, session: &Session) -> Vec<PathBuf> {
+    loop {
+        if let Some(dashboard) = &session.dashboard {
+            let picks = dashboard.take_picks();
+            if !picks.is_empty() {
+                for pick in picks {
+                    match pick {
+                        Pick::Add(symbol) if !pargs.symbols.contains(&symbol) => {
+                            plonk_log!(Info, "Hot patching `{}`", symbol);
+                            pargs.symbols.push(symbol);
+                        }
+                        Pick::Add(_) => {}
+                        Pick::Remove(symbol) => {
+                            plonk_log!(
+                                Info,
+                                "No longer patching `{}`, its last patch stays until restart",
+                                symbol
+                            );
+                            pargs.symbols.retain(|s| *s != symbol);
+                        }
+                    }
+                }
+                dashboard.update(|state| state.symbols = pargs.symbols.clone());
+                return Vec::new();
+            }
+        }
+
+        if !pargs.watch_cache.changed.is_empty()
+            || poll_changes(&mut pargs.watch_cache, Duration::from_millis(100))
+        {
+            return std::mem::take(&mut pargs.watch_cache.changed);
+        }
+
+        if let Some(running) = child {
+            if let Some(status) = plonk_child::try_wait(running).expect("Failed to wait for bin") {
+                plonk_log!(Info, "{} exited with {}", session.bin, status);
+                session.show(|state| state.phase = format!("Exited with {}", status));
+                // Ctrl-C only reaches the child, stop supervising as well.
+                if session.dashboard.is_none() && status.signal() == Some(libc::SIGINT) {
+                    exit_with(status);
+                }
+                // So was a signal sent to plonk, which the child got as well.
+                // The dashboard exits with it itself.
+                if let (None, Some(received)) = (&session.dashboard, plonk_child::received()) {
+                    exit_with(received);
+                }
+                *child = None;
+            }
+        }
+    }
+}
+
+/// Why `changed` can't be hot patched, if it can't.
+fn needs_restart(session: &Session, changed: &[PathBuf]) -> Option<String> {
+    for path in changed {
+        if matches!(
+            path.file_name().and_then(|name| name.to_str()),
+            Some("Cargo.toml" | "Cargo.lock")
+        ) {
+            return Some(format!("{} changed", path.display()));
+        }
+
+        match package_of(path, &session.packages) {
+            Some(name) if Some(name) != session.package.as_deref() => {
+                return Some(format!("crate `{}` changed", name));
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn reload(pargs: &mut Options, session: &mut Session, changed: &[PathBuf], running: bool) -> Reload {
+    if let Some(reason) = needs_restart(session, changed) {
+        return Reload::Restart(reason);
+    }
+
+    if !running {
+        return Reload::Restart("program is not running".into());
+    }
+    if pargs.symbols.is_empty() {
+        // Nothing changed when the last symbol was unpicked.
+        if changed.is_empty() {
+            return Reload::Skip;
+        }
+        return Reload::Restart("no symbol to hot patch, use -s".into());
+    }
+
+    session.show(|state| state.phase = "Building".into());
+    let started = Instant::now();
+    let artifact = match build(pargs) {
+        Some(artifact) => artifact,
+        None => {
+            session.show(|state| state.phase = "Build failed".into());
+            return Reload::Skip;
+        }
+    };
+    let build_time = started.elapsed();
+    // The build picked up whatever was saved while it ran, check that too.
+    let mut later = std::mem::take(&mut pargs.watch_cache.changed);
+    later.append(&mut pargs.watch_cache.rebuilt);
+    if let Some(reason) = needs_restart(session, &later) {
+        return Reload::Restart(reason);
+    }
+    let library_path = match pargs.watch_cache.generations.copy(artifact.filenames[0].as_ref()) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(err) => return Reload::Restart(format!("failed to copy the library: {}", err)),
+    };
+    let checked = plonk_timings::time("toolchain", || {
+        plonk_toolchain::check(
+            &mut pargs.watch_cache.bin_toolchain,
+            &session.bin,
+            &library_path,
+        )
+    });
+    if let Err(err) = checked {
+        return Reload::Restart(err);
+    }
+    let mut pairs = match plonk_timings::time("symbols", || {
+        resolve_symbols(pargs, &session.bin, &library_path)
+    }) {
+        Some(pairs) => pairs,
+        None => return Reload::Restart(format!("{:?} can't be patched", pargs.symbols)),
+    };
+    let calls = plonk_timings::time("calls", || {
+        plonk_calls::plan(&mut pargs.watch_cache.bin_functions, &session.bin, &mut pairs)
+    });
+    let calls = calls.unwrap_or_else(|err| {
+        plonk_log!(Warn, "Hooking every function directly: {}", err);
+        String::new()
+    });
+    if pairs.is_empty() {
+        return Reload::Restart(format!("{:?} can't be patched", pargs.symbols));
+    }
+    let checked = plonk_timings::time("signatures", || {
+        plonk_dwarf::check_signatures(
+            &mut pargs.watch_cache.bin_signatures,
+            &session.bin,
+            &library_path,
+            &pairs,
+        )
+    });
+    if let Err(err) = checked {
+        return Reload::Restart(err);
+    }
+
+    let olds = pairs
+        .iter()
+        .map(|(old, _)| old.as_str())
+        .collect::<Vec<_>>();
+    let inlined = plonk_timings::time("inlining", || {
+        plonk_dwarf::inlined(&mut pargs.watch_cache.bin_inlined, &session.bin, &olds)
+    });
+    match inlined {
+        Ok(calls) if !calls.is_empty() => {
+            let packages = warn_inlined(&mut pargs.watch_cache.inlined_warned, &calls);
+            let fresh = packages
+                .iter()
+                .filter(|package| !session.no_inline.contains(*package))
+                .cloned()
+                .collect::<Vec<_>>();
+            if pargs.no_inline && !fresh.is_empty() {
+                session.no_inline.extend(fresh.iter().cloned());
+                return Reload::Restart(format!(
+                    "rebuilding {:?} without inlining patched functions",
+                    fresh
+                ));
+            }
+            if !fresh.is_empty() {
+                plonk_log!(
+                    Warn,
+                    "Pass --no-inline to rebuild {:?} without inlining",
+                    fresh
+                );
+            }
+        }
+        Ok(_) => {}
+        Err(err) => plonk_log!(Debug, "Not looking for inlined calls: {}", err),
+    }
+
+    let bindings = plonk_timings::time("statics", || {
+        plonk_bind::bindings(
+            &mut pargs.watch_cache.bin_statics,
+            &session.bin,
+            &library_path,
+            &pargs.package,
+        )
+    });
+    let bindings = bindings.unwrap_or_else(|err| {
+        plonk_log!(Warn, "Patched code gets its own statics: {}", err);
+        String::new()
+    });
+
+    let mut patch = format!("patch\t{}\t{}\t{}", library_path, bindings, calls);
+    for (old_symbol, new_symbol) in &pairs {
+        patch.push_str(&format!("\t{}\t{}", old_symbol, new_symbol));
+    }
+    patch.push('\n');
+
+    let started = Instant::now();
+    match send(&session.control, &patch) {
+        Ok(report) => {
+            let patch_time = started.elapsed();
+            plonk_timings::injector(&report);
+            let mut fields = report.split('\t').skip(2);
+            if let Some(generation) = fields.next().and_then(|field| field.parse().ok()) {
+                session.generation = generation;
+            }
+            // Followed by the copies the injector was done with.
+            for path in fields {
+                plonk_log!(Debug, "Unloaded {}", path);
+                pargs.watch_cache.generations.remove(path);
+            }
+            plonk_log!(
+                Info,
+                "Hot patched {:?}, generation {}",
+                pargs.symbols,
+                session.generation
+            );
+
+            let generation = session.generation;
+            let symbols = pargs.symbols.clone();
+            session.show(|state| {
+                state.phase = "Running".into();
+                state.build_time = Some(build_time);
+                state.patch_time = Some(patch_time);
+                state.generation = generation;
+                for symbol in symbols {
+                    state.patched.insert(symbol, generation);
+                }
+            });
+            Reload::Patched
+        }
+        Err(err) => Reload::Restart(format!("hot patch failed: {}", err)),
+    }
+}
+
+/// `cargo plonk revert`: put the binary's own code, or the patch from
+/// generation `--to`, back in the program of a running supervise session.
+pub fn revert(pargs: &mut Options) -> bool {
+    // Plain `run --watch` sessions have no injector listening.
+    let sessions = plonk_generations::sessions().map(|pids| {
+        pids.into_iter()
+            .filter(|pid| control_path(*pid).is_ok_and(|control| control.exists()))
+            .collect::<Vec<_>>()
+    });
+    let pid = match sessions.as_deref() {
+        Ok([pid]) => *pid,
+        Ok([]) => {
+            plonk_log!(Error, "No program to revert, start one with `run --watch --supervise`");
+            return false;
+        }
+        Ok(pids) => {
+            plonk_log!(Error, "More than one plonk session is running: {:?}", pids);
+            return false;
+        }
+        Err(err) => {
+            plonk_log!(Error, "Failed to find plonk sessions: {}", err);
+            return false;
+        }
+    };
+
+    let generation = pargs.revert_to.unwrap_or(0);
+    let mut command = format!("revert\t{}", generation);
+    if !pargs.symbols.is_empty() {
+        let (_, bin) = match find_bin(pargs) {
+            Some(bin) => bin,
+            None => return false,
+        };
+        for symbol in &pargs.symbols {
+            match find_symbol(&bin, &pargs.package, symbol) {
+                Some(name) => command.push_str(&format!("\t{}", name)),
+                None => {
+                    plonk_log!(Error, "Failed to find function symbol `{}` in {}", symbol, bin);
+                    plonk_missing::explain(&pargs.package, symbol, &bin, None);
+                    return false;
+                }
+            }
+        }
+    }
+    command.push('\n');
+
+    let what = if pargs.symbols.is_empty() {
+        "every patched function".to_string()
+    } else {
+        format!("{:?}", pargs.symbols)
+    };
+    match control_path(pid).and_then(|control| send(&control, &command)) {
+        Ok(_) if generation == 0 => {
+            plonk_log!(Info, "Reverted {} to the original code", what);
+            true
+        }
+        Ok(_) => {
+            plonk_log!(Info, "Reverted {} to generation {}", what, generation);
+            true
+        }
+        Err(err) => {
+            plonk_log!(Error, "Failed to revert: {}", err);
+            false
+        }
+    }
+}
+
+/// Socket the injector of the program supervised by plonk `pid` listens on,
+/// where no other user can bind it first.
+fn control_path(pid: u32) -> Result<PathBuf, String> {
+    Ok(private_temp_dir()?.join(format!("{}.sock", pid)))
+}
+
+/// Remove the control socket of this session, if it has one.
+pub fn remove_control() {
+    if let Ok(control) = control_path(std::process::id()) {
+        let _ = std::fs::remove_file(control);
+    }
+}
+
+/// Send a command to the injector and wait for its reply, returns what
+/// follows `ok`.
+fn send(control: &Path, command: &str) -> Result<String, String> {
+    let mut stream = UnixStream::connect(control)
+        .map_err(|err| format!("injector is not listening on {}: {}", control.display(), err))?;
+    stream
+        .write_all(command.as_bytes())
+        .map_err(|err| err.to_string())?;
+
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .map_err(|err| err.to_string())?;
+
+    match reply.trim_end().split_once('\t') {
+        _ if reply.trim_end() == "ok" => Ok(String::new()),
+        Some(("ok", report)) => Ok(report.to_string()),
+        Some(("error", message)) => Err(message.to_string()),
+        _ => Err(format!("unexpected reply: {:?}", reply)),
+    }
+}
+
+/// Local package owning `path`, the innermost one for nested packages.
+fn package_of<'a>(path: &Path, packages: &'a [(String, PathBuf)]) -> Option<&'a str> {
+    packages
+        .iter()
+        .filter(|(_, dir)| path.starts_with(dir))
+        .max_by_key(|(_, dir)| dir.components().count())
+        .map(|(name, _)| name.as_str())
+}
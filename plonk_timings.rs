@@ -0,0 +1,216 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Per-reload timings for `--timings` */
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use serde_json::json;
+
+/// Set while `--timings` is on.
+static SESSION: Mutex<Option<Timings>> = Mutex::new(None);
+
+#[derive(Default)]
+struct Timings {
+    /// When the change being reloaded was saved.
+    edited: Option<Instant>,
+    /// Phases of the reload in progress, in order.
+    phases: Vec<(&'static str, Duration)>,
+    reloads: Vec<Reload>,
+    json: Option<PathBuf>,
+}
+
+struct Reload {
+    phases: Vec<(&'static str, Duration)>,
+    /// From saving the change to the program running it.
+    latency: Option<Duration>,
+}
+
+pub fn enable(json: Option<PathBuf>) {
+    *SESSION.lock().unwrap() = Some(Timings {
+        json,
+        ..Default::default()
+    });
+}
+
+pub fn enabled() -> bool {
+    SESSION.lock().unwrap().is_some()
+}
+
+/// Start a reload for a change saved at `edited`.
+pub fn start(edited: Option<Instant>) {
+    if let Some(timings) = SESSION.lock().unwrap().as_mut() {
+        timings.edited = edited;
+    }
+}
+
+pub fn phase(name: &'static str, duration: Duration) {
+    if let Some(timings) = SESSION.lock().unwrap().as_mut() {
+        timings.phases.push((name, duration));
+    }
+}
+
+pub fn time<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let started = Instant::now();
+    let result = f();
+    phase(name, started.elapsed());
+    result
+}
+
+/// Record a cargo build of `artifact` that started at `since` and took
+/// `total`. The linker creates the library and writes it last, so its birth
+/// and modification times bracket the link.
+pub fn build(total: Duration, since: SystemTime, artifact: &Path) {
+    let link = std::fs::metadata(artifact).ok().and_then(|meta| {
+        let created = meta.created().ok()?;
+        // Not relinked by this build.
+        created.duration_since(since).ok()?;
+        meta.modified().ok()?.duration_since(created).ok()
+    });
+
+    match link {
+        Some(link) if link <= total => {
+            phase("compile", total - link);
+            phase("link", link);
+        }
+        _ => phase("compile", total),
+    }
+}
+
+/// Record the injector's `<load us>\t<patch us>` report.
+pub fn injector(report: &str) {
+    let mut fields = report
+        .trim_end()
+        .split('\t')
+        .map(|field| field.parse().map(Duration::from_micros));
+    if let (Some(Ok(load)), Some(Ok(patch))) = (fields.next(), fields.next()) {
+        phase("load", load);
+        phase("patch", patch);
+    }
+}
+
+/// Record the report the injector writes to `PLONK_TIMINGS` once it patched
+/// the program at startup, returns whether it was there.
+pub fn injector_file(path: &Path) -> bool {
+    match std::fs::read_to_string(path) {
+        Ok(report) if report.ends_with('\n') => {
+            let _ = std::fs::remove_file(path);
+            injector(&report);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Print the breakdown of the reload in progress. `patched` is whether it
+/// ended with the program running the change.
+pub fn finish(patched: bool) {
+    let mut session = SESSION.lock().unwrap();
+    let timings = match session.as_mut() {
+        Some(timings) if !timings.phases.is_empty() => timings,
+        _ => return,
+    };
+
+    let latency = match timings.edited.take() {
+        Some(edited) if patched => Some(edited.elapsed()),
+        _ => None,
+    };
+    let mut line = timings
+        .phases
+        .iter()
+        .map(|(name, duration)| format!("{} {:.2?}", name, duration))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(latency) = latency {
+        line.push_str(&format!(", edit to patch {:.2?}", latency));
+    }
+    // Asked for with --timings, so shown at the default level.
+    plonk_log!(Warn, "Timings: {}", line);
+
+    timings.reloads.push(Reload {
+        phases: std::mem::take(&mut timings.phases),
+        latency,
+    });
+}
+
+/// Print the edit to patch latency of the session and write the JSON report.
+pub fn summary() {
+    let timings = match SESSION.lock().unwrap().take() {
+        Some(timings) => timings,
+        None => return,
+    };
+
+    let mut latencies = timings
+        .reloads
+        .iter()
+        .filter_map(|reload| reload.latency)
+        .collect::<Vec<_>>();
+    latencies.sort();
+    let p50 = percentile(&latencies, 0.50);
+    let p95 = percentile(&latencies, 0.95);
+    if let (Some(p50), Some(p95)) = (p50, p95) {
+        plonk_log!(
+            Warn,
+            "{} reloads, edit to patch p50 {:.2?}, p95 {:.2?}",
+            timings.reloads.len(),
+            p50,
+            p95
+        );
+    }
+
+    let path = match &timings.json {
+        Some(path) => path,
+        None => return,
+    };
+    let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let reloads = timings
+        .reloads
+        .iter()
+        .map(|reload| {
+            let phases = reload
+                .phases
+                .iter()
+                .map(|(name, duration)| json!({ "phase": name, "ms": millis(*duration) }))
+                .collect::<Vec<_>>();
+            json!({ "phases": phases, "edit_to_patch_ms": reload.latency.map(millis) })
+        })
+        .collect::<Vec<_>>();
+    let report = json!({
+        "reloads": reloads,
+        "edit_to_patch_p50_ms": p50.map(millis),
+        "edit_to_patch_p95_ms": p95.map(millis),
+    });
+    if let Err(err) = std::fs::write(path, format!("{:#}\n", report)) {
+        plonk_log!(Error, "Failed to write {}: {}", path.display(), err);
+    }
+}
+
+/// Nearest-rank percentile of sorted durations.
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    let rank = (sorted.len() as f64 * p).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
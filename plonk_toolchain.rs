@@ -0,0 +1,169 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Making sure the binary and the patch library come from the same toolchain */
+
+use std::collections::HashSet;
+
+use object::Object;
+use object::ObjectSection;
+use object::ObjectSymbol;
+
+/// What the binary was built with, loaded on the first check.
+pub struct Toolchain {
+    /// `rustc 1.75.0 (82e1608df 2023-12-21)`, `None` if the binary doesn't
+    /// say.
+    version: Option<String>,
+    /// Crate hash of the std linked into the binary, `None` for legacy
+    /// symbol mangling.
+    std: Option<String>,
+    /// libstd files already known to match the binary's std.
+    checked: HashSet<String>,
+}
+
+impl Toolchain {
+    fn load(bin: &str) -> Result<Self, String> {
+        let data = std::fs::read(bin).map_err(|err| format!("Failed to read {}: {}", bin, err))?;
+        let file = object::File::parse(&*data)
+            .map_err(|err| format!("Failed to parse {}: {}", bin, err))?;
+
+        Ok(Self {
+            version: version(&file),
+            std: file
+                .symbols()
+                .find_map(|symbol| std_hash(symbol.name().ok()?)),
+            checked: HashSet::new(),
+        })
+    }
+}
+
+/// Check that the library at `library_path` was built by the same rustc as
+/// `bin`, and that the libstd it links is the std inside `bin`. Fails with
+/// an explanation instead of letting `dlopen` or the patched code do so.
+pub fn check(cache: &mut Option<Toolchain>, bin: &str, library_path: &str) -> Result<(), String> {
+    let toolchain = match cache {
+        Some(toolchain) => toolchain,
+        None => cache.insert(Toolchain::load(bin)?),
+    };
+
+    let data = std::fs::read(library_path)
+        .map_err(|err| format!("Failed to read {}: {}", library_path, err))?;
+    let file = object::File::parse(&*data)
+        .map_err(|err| format!("Failed to parse {}: {}", library_path, err))?;
+
+    if let (Some(ours), Some(theirs)) = (version(&file), &toolchain.version) {
+        if ours != *theirs {
+            return Err(format!(
+                "{} was built with {} but the library with {}, rebuild it with the same toolchain \
+                 or pick that one for plonk with `rust-toolchain.toml`, `cargo +toolchain` or \
+                 `$RUSTC`",
+                bin, theirs, ours
+            ));
+        }
+    }
+
+    let libstd = match needed_libstd(&file) {
+        Some(libstd) => libstd,
+        None => return Ok(()),
+    };
+    if toolchain.checked.contains(&libstd) {
+        return Ok(());
+    }
+
+    let libdir = crate::rustc_libdir();
+    let path = libdir.join(&libstd);
+    let data = std::fs::read(&path).map_err(|_| {
+        format!(
+            "the library needs {} which isn't in {}, the lib directory of {}",
+            libstd,
+            libdir.display(),
+            crate::rustc_version()
+        )
+    })?;
+    let file = object::File::parse(&*data)
+        .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+
+    let found = file
+        .dynamic_symbols()
+        .find_map(|symbol| std_hash(symbol.name().ok()?));
+    if let (Some(ours), Some(theirs)) = (found, &toolchain.std) {
+        if ours != *theirs {
+            return Err(format!(
+                "{} has std[{}] built in but the library links {} with std[{}], the same rustc \
+                 built std differently for them",
+                bin, theirs, libstd, ours
+            ));
+        }
+    }
+
+    toolchain.checked.insert(libstd);
+    Ok(())
+}
+
+/// rustc version recorded in the `.comment` section, ELF only.
+fn version(file: &object::File) -> Option<String> {
+    let comment = file.section_by_name(".comment")?.data().ok()?;
+    comment
+        .split(|byte| *byte == 0)
+        .filter_map(|entry| std::str::from_utf8(entry).ok())
+        .find_map(|entry| entry.strip_prefix("rustc version "))
+        .map(|version| format!("rustc {}", version))
+}
+
+/// File name of the libstd the library loads.
+fn needed_libstd(file: &object::File) -> Option<String> {
+    let is_libstd = |name: &str| name.starts_with("libstd-");
+    // ELF lists needed libraries in the dynamic string table, Mach-O binds
+    // imports to them.
+    if let Some(dynstr) = file.section_by_name(".dynstr") {
+        let dynstr = dynstr.data().ok()?;
+        return dynstr
+            .split(|byte| *byte == 0)
+            .filter_map(|entry| std::str::from_utf8(entry).ok())
+            .find(|name| is_libstd(name))
+            .map(String::from);
+    }
+    file.imports().ok()?.iter().find_map(|import| {
+        let library = std::str::from_utf8(import.library()).ok()?;
+        let name = library.rsplit('/').next()?;
+        is_libstd(name).then(|| name.to_string())
+    })
+}
+
+/// Crate hash of std in a v0 mangled symbol, `_RNvNtCs1234_3std2io5stdout`
+/// demangles to `std[1234]::io::stdout`.
+fn std_hash(symbol: &str) -> Option<String> {
+    if !symbol.starts_with("_R") || !symbol.contains("_3std") {
+        return None;
+    }
+    let path = rustc_demangle::try_demangle(symbol).ok()?.to_string();
+    path.match_indices("std[").find_map(|(start, _)| {
+        // Not the end of another crate's name, like `rustc_std[..]`.
+        if let Some(c) = path[..start].chars().next_back() {
+            if c.is_alphanumeric() || c == '_' {
+                return None;
+            }
+        }
+        let hash = &path[start + "std[".len()..];
+        Some(hash[..hash.find(']')?].to_string())
+    })
+}
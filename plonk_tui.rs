@@ -0,0 +1,320 @@
+/*
+ * Copyright (c) 2023 Divy Srivastava
+ *
+ * Permission is hereby granted, free of charge, to any person obtaining a copy
+ * of this software and associated documentation files (the "Software"), to deal
+ * in the Software without restriction, including without limitation the rights
+ * to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ * copies of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be included in
+ * all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ * IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ * FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL
+ * THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ * LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ * OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ * THE SOFTWARE.
+ */
+
+/* Terminal dashboard for `run --watch --tui` */
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use fuzzy_matcher::skim::SkimMatcherV2;
+use fuzzy_matcher::FuzzyMatcher;
+use ratatui::crossterm::event;
+use ratatui::crossterm::event::Event;
+use ratatui::crossterm::event::KeyCode;
+use ratatui::crossterm::event::KeyEvent;
+use ratatui::crossterm::event::KeyEventKind;
+use ratatui::crossterm::event::KeyModifiers;
+use ratatui::layout::Constraint;
+use ratatui::layout::Layout;
+use ratatui::layout::Rect;
+use ratatui::style::Modifier;
+use ratatui::style::Style;
+use ratatui::text::Line;
+use ratatui::widgets::Block;
+use ratatui::widgets::Clear;
+use ratatui::widgets::List;
+use ratatui::widgets::ListState;
+use ratatui::widgets::Paragraph;
+use ratatui::Frame;
+
+use crate::plonk_child;
+use crate::plonk_supervise;
+use crate::set_log_tap;
+use crate::Options;
+
+/// Lines kept for the output and log panes.
+const SCROLLBACK: usize = 1000;
+
+/// Picker entries shown at once.
+const MATCHES: usize = 50;
+
+/// A change to the hot patched symbols made in the dashboard.
+pub enum Pick {
+    Add(String),
+    Remove(String),
+}
+
+/// What the supervisor reports to the dashboard.
+#[derive(Default)]
+pub struct State {
+    pub phase: String,
+    pub build_time: Option<Duration>,
+    pub patch_time: Option<Duration>,
+    /// Generation of the last patch.
+    pub generation: u32,
+    /// Symbols being hot patched.
+    pub symbols: Vec<String>,
+    /// Generation each symbol was last patched in.
+    pub patched: HashMap<String, u32>,
+    /// Functions that can be picked.
+    pub candidates: Vec<String>,
+    /// Program output.
+    output: VecDeque<String>,
+    /// Plonk and cargo output.
+    log: VecDeque<String>,
+    picks: Vec<Pick>,
+}
+
+impl State {
+    pub fn output(&mut self, line: String) {
+        push(&mut self.output, line);
+    }
+}
+
+fn push(lines: &mut VecDeque<String>, line: String) {
+    if lines.len() == SCROLLBACK {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+#[derive(Default)]
+pub struct Dashboard(Mutex<State>);
+
+impl Dashboard {
+    pub fn update(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.0.lock().unwrap());
+    }
+
+    pub fn take_picks(&self) -> Vec<Pick> {
+        std::mem::take(&mut self.0.lock().unwrap().picks)
+    }
+}
+
+#[derive(Default)]
+struct Ui {
+    /// Selected symbol in the patched list.
+    selected: usize,
+    picker: Option<Picker>,
+}
+
+#[derive(Default)]
+struct Picker {
+    query: String,
+    selected: usize,
+}
+
+/// Supervise the program in the background and show what it's doing.
+pub fn dashboard(pargs: &mut Options) -> Option<ExitStatus> {
+    let dashboard = Arc::new(Dashboard::default());
+
+    let tap = dashboard.clone();
+    set_log_tap(Some(Box::new(move |line| {
+        tap.update(|state| push(&mut state.log, line))
+    })));
+
+    let mut pargs = std::mem::take(pargs);
+    let shared = dashboard.clone();
+    let supervisor = std::thread::spawn(move || {
+        plonk_supervise::supervise(&mut pargs, Some(shared));
+    });
+
+    let mut terminal = ratatui::init();
+    let mut ui = Ui::default();
+    while !supervisor.is_finished() && plonk_child::received().is_none() {
+        terminal
+            .draw(|frame| draw(frame, &dashboard, &mut ui))
+            .expect("Failed to draw dashboard");
+
+        if !event::poll(Duration::from_millis(100)).expect("Failed to read terminal") {
+            continue;
+        }
+        if let Event::Key(key) = event::read().expect("Failed to read terminal") {
+            if key.kind == KeyEventKind::Press && !handle(key, &dashboard, &mut ui) {
+                break;
+            }
+        }
+    }
+    ratatui::restore();
+    // The timings summary on exit goes to the terminal again.
+    set_log_tap(None);
+    plonk_child::release();
+
+    // The supervisor only returns on errors, which went to the dashboard.
+    if supervisor.is_finished() {
+        dashboard.update(|state| state.log.iter().for_each(|line| eprintln!("{}", line)));
+        return None;
+    }
+    Some(plonk_child::received().unwrap_or_default())
+}
+
+/// Handle a key press, returns false to quit.
+fn handle(key: KeyEvent, dashboard: &Dashboard, ui: &mut Ui) -> bool {
+    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+        return false;
+    }
+
+    let mut state = dashboard.0.lock().unwrap();
+    if let Some(picker) = &mut ui.picker {
+        match key.code {
+            KeyCode::Esc => ui.picker = None,
+            KeyCode::Enter => {
+                if let Some(symbol) = matches(&state.candidates, &picker.query).get(picker.selected)
+                {
+                    let symbol = symbol.to_string();
+                    state.picks.push(Pick::Add(symbol));
+                }
+                ui.picker = None;
+            }
+            KeyCode::Up => picker.selected = picker.selected.saturating_sub(1),
+            KeyCode::Down => picker.selected += 1,
+            KeyCode::Backspace => {
+                picker.query.pop();
+                picker.selected = 0;
+            }
+            KeyCode::Char(c) => {
+                picker.query.push(c);
+                picker.selected = 0;
+            }
+            _ => {}
+        }
+        return true;
+    }
+
+    match key.code {
+        KeyCode::Char('q') => return false,
+        KeyCode::Char('a') | KeyCode::Char('/') => ui.picker = Some(Picker::default()),
+        KeyCode::Char('d') | KeyCode::Delete => {
+            if let Some(symbol) = state.symbols.get(ui.selected) {
+                let symbol = symbol.clone();
+                state.picks.push(Pick::Remove(symbol));
+            }
+        }
+        KeyCode::Up | KeyCode::Char('k') => ui.selected = ui.selected.saturating_sub(1),
+        KeyCode::Down | KeyCode::Char('j') => ui.selected += 1,
+        _ => {}
+    }
+    true
+}
+
+/// Candidates matching `query`, best first.
+fn matches<'a>(candidates: &'a [String], query: &str) -> Vec<&'a str> {
+    let matcher = SkimMatcherV2::default();
+    let mut scored = candidates
+        .iter()
+        .filter_map(|name| Some((matcher.fuzzy_match(name, query)?, name.as_str())))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    scored.into_iter().take(MATCHES).map(|(_, name)| name).collect()
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard, ui: &mut Ui) {
+    let state = dashboard.0.lock().unwrap();
+    let [status, body, footer] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [symbols, panes] =
+        Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)]).areas(body);
+    let [output, log] =
+        Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(panes);
+
+    let timing = |time: Option<Duration>| match time {
+        Some(time) => format!("{:.2?}", time),
+        None => "-".into(),
+    };
+    let line = format!(
+        "{}   build {}   patch {}   generation {}",
+        state.phase,
+        timing(state.build_time),
+        timing(state.patch_time),
+        state.generation
+    );
+    frame.render_widget(Paragraph::new(line).block(Block::bordered().title(" plonk ")), status);
+
+    ui.selected = ui.selected.min(state.symbols.len().saturating_sub(1));
+    let items = state.symbols.iter().map(|symbol| match state.patched.get(symbol) {
+        Some(generation) => format!("{}  #{}", symbol, generation),
+        None => format!("{}  (pending)", symbol),
+    });
+    let list = List::new(items)
+        .block(Block::bordered().title(" Patched "))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    let mut list_state = ListState::default().with_selected(Some(ui.selected));
+    frame.render_stateful_widget(list, symbols, &mut list_state);
+
+    render_lines(frame, output, " Output ", &state.output);
+    render_lines(frame, log, " Log ", &state.log);
+
+    let keys = match ui.picker {
+        Some(_) => " type to search   ↑↓ select   enter patch   esc cancel",
+        None => " a add symbol   d remove   ↑↓ select   q quit",
+    };
+    frame.render_widget(Line::from(keys), footer);
+
+    if let Some(picker) = &mut ui.picker {
+        let found = matches(&state.candidates, &picker.query);
+        picker.selected = picker.selected.min(found.len().saturating_sub(1));
+
+        let area = centered(frame.area(), 60, 20);
+        let [query, results] =
+            Layout::vertical([Constraint::Length(3), Constraint::Min(0)]).areas(area);
+        frame.render_widget(Clear, area);
+        frame.render_widget(
+            Paragraph::new(picker.query.as_str()).block(Block::bordered().title(" Symbol ")),
+            query,
+        );
+        let list = List::new(found)
+            .block(Block::bordered())
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut list_state = ListState::default().with_selected(Some(picker.selected));
+        frame.render_stateful_widget(list, results, &mut list_state);
+    }
+}
+
+/// Render the last lines that fit in `area`.
+fn render_lines(frame: &mut Frame, area: Rect, title: &str, lines: &VecDeque<String>) {
+    let height = area.height.saturating_sub(2) as usize;
+    let text = lines
+        .iter()
+        .skip(lines.len().saturating_sub(height))
+        .map(|line| Line::from(line.as_str()))
+        .collect::<Vec<_>>();
+    frame.render_widget(Paragraph::new(text).block(Block::bordered().title(title)), area);
+}
+
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}